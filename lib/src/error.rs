@@ -1,4 +1,9 @@
-use std::{array::TryFromSliceError, net::AddrParseError, result::Result as StdResult};
+use std::{
+    array::TryFromSliceError,
+    net::AddrParseError,
+    path::{Path, PathBuf},
+    result::Result as StdResult,
+};
 
 
 pub type Result<T, E = Error> = StdResult<T, E>;
@@ -17,13 +22,33 @@ pub enum ErrorKind {
     File,
     Unknown,
     Socket,
+    Options,
+    // the segments are held by snapshots
+    Snapshot,
+    // begin inside a transaction, or commit or rollback outside of one
+    Transaction,
+    // the checksum doesn't match the bytes at `offset` of `file`
+    Corruption { file: PathBuf, offset: u64 },
+    // no keyspace of the name, or one already, or the name is not valid
+    Keyspace,
+}
+
+impl Error {
+    pub fn corruption(file: &Path, offset: u64) -> Self {
+        Error {
+            kind: ErrorKind::Corruption {
+                file: file.to_path_buf(),
+                offset,
+            },
+        }
+    }
 }
 
 impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.kind {
+        match &self.kind {
             ErrorKind::Parser => {
                 write!(f, "invalid sql")
             }
@@ -45,6 +70,21 @@ impl std::fmt::Display for Error {
             ErrorKind::Unknown => {
                 write!(f, "unknown error")
             }
+            ErrorKind::Options => {
+                write!(f, "invalid storage options")
+            }
+            ErrorKind::Snapshot => {
+                write!(f, "release the snapshots first")
+            }
+            ErrorKind::Transaction => {
+                write!(f, "not valid in the state of the transaction")
+            }
+            ErrorKind::Corruption { file, offset } => {
+                write!(f, "corrupted data in {} at {}", file.display(), offset)
+            }
+            ErrorKind::Keyspace => {
+                write!(f, "no such keyspace, or the name is taken or not valid")
+            }
         }
     }
 }
@@ -1,27 +1,67 @@
-use super::{parser, statement::Keyword, storage::Storage, storage::FileSystem};
-use crate::{report::Report, response::Response, Result};
-use std::time::Instant;
+use super::{
+    keyspace::{self, Keyspaces},
+    parser,
+    statement::Keyword,
+    storage::Options,
+    storage::Record,
+    storage::Snapshot,
+    storage::Storage,
+    storage::WriteBatch,
+    utils::now,
+};
+use crate::{report::Report, response::Response, session::Session, Error, ErrorKind, Result};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::{Duration, Instant};
 
 pub struct Executor {
-    storage: Storage<FileSystem>,
+    // a storage for every keyspace, the sessions pick theirs with `use`
+    keyspaces: Keyspaces,
+    // the session of `execute`, for a single client
+    session: Session,
 }
 
 impl Executor {
     pub async fn new(path: &str) -> Result<Self> {
+        Executor::with_options(path, Options::default()).await
+    }
+
+    /// see `Builder` for the options
+    pub async fn with_options(path: &str, options: Options) -> Result<Self> {
+        Ok(Executor {
+            keyspaces: Keyspaces::open(path, options)?,
+            session: Session::default(),
+        })
+    }
+
+    /// keep everything in memory, nothing is written to the disk and nothing survives the executor
+    pub async fn in_memory(options: Options) -> Result<Self> {
         Ok(Executor {
-            storage: Storage::new(path, "data")?,
+            keyspaces: Keyspaces::in_memory(options)?,
+            session: Session::default(),
         })
     }
     #[cfg(feature = "test")]
     pub async fn new(storage: Storage) -> Result<Self> {
-        Ok(Executor { storage })
+        Ok(Executor {
+            keyspaces: storage.into(),
+            session: Session::default(),
+        })
     }
 }
 
 impl Executor {
     pub fn execute(&mut self, line: &str) -> Report {
+        let mut session = std::mem::take(&mut self.session);
+        let report = self.execute_in(&mut session, line);
+        self.session = session;
+        report
+    }
+
+    /// execute the statement on behalf of a client, which keeps its state in the session
+    pub fn execute_in(&mut self, session: &mut Session, line: &str) -> Report {
         let now = Instant::now();
-        let res = self.execute_internal(line);
+        let res = self.execute_internal(session, line);
         let time_elapsed = now.elapsed();
         Report {
             time_elapsed,
@@ -34,23 +74,299 @@ impl Executor {
         }
     }
 
-    pub fn execute_internal(&mut self, line: &str) -> Result<Response> {
+    pub fn execute_internal(&mut self, session: &mut Session, line: &str) -> Result<Response> {
         let (_, statement) = parser::parse_sql(line)?;
         let response = match statement.verb {
-            Keyword::Clear => self.storage.clear()?,
+            Keyword::Clear => self.storage_mut(session)?.clear()?,
+            // only the default keyspace moves, see `Keyspaces::move_to`
+            Keyword::MoveFile => {
+                in_default(session)?;
+                self.keyspaces.move_to(&statement.key)?;
+                Response::Ok
+            }
+            Keyword::AttachFile => {
+                in_default(session)?;
+                self.keyspaces.attach_to(&statement.key)?;
+                Response::Ok
+            }
             Keyword::Set => {
-                // should be a transaction;
-                // todo: this statement should be inside set() method
-                self.storage.fs.wal(line)?;
-                self.storage
-                    .set(statement.key.as_bytes(), statement.value.as_bytes())?
-            }
-            Keyword::Get => self.storage.get(statement.key.as_bytes())?,
-            _ => Response::Ok,
+                let (key, value) = (statement.key.as_bytes(), statement.value.as_bytes());
+                match (&session.transaction, statement.ttl) {
+                    (None, None) => self.storage_mut(session)?.set(key, value)?,
+                    (_, ttl) => {
+                        let mut batch = WriteBatch::new();
+                        match ttl {
+                            Some(ttl) => batch.put_with_ttl(key, value, Duration::from_secs(ttl)),
+                            None => batch.put(key, value),
+                        };
+                        self.write(session, batch)?;
+                        Response::Record {
+                            key: key.to_vec(),
+                            value: value.to_vec(),
+                        }
+                    }
+                }
+            }
+            Keyword::MultiSet => {
+                let batch = statement
+                    .pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.into_bytes(), Some(value.into_bytes())))
+                    .collect();
+                self.write(session, batch)?;
+                Response::Ok
+            }
+            Keyword::Get => self.get(session, statement.key.as_bytes())?,
+            Keyword::Delete => match &session.transaction {
+                Some(_) => {
+                    let mut batch = WriteBatch::new();
+                    batch.delete(statement.key.as_bytes());
+                    self.write(session, batch)?;
+                    Response::Ok
+                }
+                None => self.storage_mut(session)?.del(statement.key.as_bytes())?,
+            },
+            // the latest value gets the expiry, rather than the one of the snapshot
+            Keyword::Expire => {
+                let key = statement.key.as_bytes();
+                let record = self.lookup(session, key, None)?.ok_or(Error {
+                    kind: ErrorKind::Key,
+                })?;
+                let mut batch = WriteBatch::new();
+                let ttl = Duration::from_secs(statement.ttl.unwrap_or_default());
+                batch.put_with_ttl(key, &record.value, ttl);
+                self.write(session, batch)?;
+                Response::Ok
+            }
+            // the seconds left, -1 if the key never expires
+            Keyword::Ttl => {
+                let key = statement.key.as_bytes();
+                let record = self
+                    .lookup(session, key, session.snapshot.as_ref())?
+                    .ok_or(Error {
+                        kind: ErrorKind::Key,
+                    })?;
+                let ttl = match record.expires {
+                    Some(expires) => expires.saturating_sub(now()).div_ceil(1000).to_string(),
+                    None => "-1".to_string(),
+                };
+                Response::Record {
+                    key: key.to_vec(),
+                    value: ttl.into_bytes(),
+                }
+            }
+            Keyword::Scan => self.scan(
+                session,
+                statement.key.as_bytes(),
+                statement.value.as_bytes(),
+                statement.limit,
+            )?,
+            Keyword::Compact => self.storage_mut(session)?.compact()?,
+            Keyword::Stats => self.storage(session)?.stats()?,
+            // a snapshot taken again replaces the old one
+            Keyword::Snapshot => {
+                let snapshot = self.storage(session)?.snapshot();
+                let seq = snapshot.seq;
+                session.snapshot = Some(snapshot);
+                Response::Record {
+                    key: b"snapshot".to_vec(),
+                    value: seq.to_string().into_bytes(),
+                }
+            }
+            Keyword::Release => {
+                session.snapshot = None;
+                Response::Ok
+            }
+            Keyword::Begin => {
+                if session.transaction.is_some() {
+                    return Err(Error {
+                        kind: ErrorKind::Transaction,
+                    });
+                }
+                session.transaction = Some(BTreeMap::new());
+                Response::Ok
+            }
+            Keyword::Commit => {
+                let writes = session.transaction.take().ok_or(Error {
+                    kind: ErrorKind::Transaction,
+                })?;
+                self.storage_mut(session)?
+                    .write_batch(writes.into_values().collect())?;
+                Response::Ok
+            }
+            Keyword::Rollback => {
+                session.transaction.take().ok_or(Error {
+                    kind: ErrorKind::Transaction,
+                })?;
+                Response::Ok
+            }
+            Keyword::CreateKeyspace => {
+                self.keyspaces.create(&statement.key)?;
+                Response::Ok
+            }
+            // the sessions still using it fail until they pick another one
+            Keyword::DropKeyspace => {
+                self.keyspaces.remove(&statement.key)?;
+                Response::Ok
+            }
+            // the snapshot and the transaction belong to the keyspace they were started in
+            Keyword::Use => {
+                if session.snapshot.is_some() {
+                    return Err(Error {
+                        kind: ErrorKind::Snapshot,
+                    });
+                }
+                if session.transaction.is_some() {
+                    return Err(Error {
+                        kind: ErrorKind::Transaction,
+                    });
+                }
+                self.keyspaces.get(&statement.key)?;
+                session.keyspace = Some(statement.key);
+                Response::Ok
+            }
+            // the keyspace of the session is marked as the current one
+            Keyword::ListKeyspaces => Response::Records {
+                records: self
+                    .keyspaces
+                    .names()
+                    .map(|name| {
+                        let current = match name == session.keyspace() {
+                            true => b"current".to_vec(),
+                            false => vec![],
+                        };
+                        (name.as_bytes().to_vec(), current)
+                    })
+                    .collect(),
+            },
         };
         Ok(response)
     }
-    pub fn merge(&mut self) {}
+
+    // the storage of the keyspace the session uses
+    fn storage(&self, session: &Session) -> Result<&Storage> {
+        self.keyspaces.get(session.keyspace())
+    }
+
+    fn storage_mut(&mut self, session: &Session) -> Result<&mut Storage> {
+        self.keyspaces.get_mut(session.keyspace())
+    }
+
+    // the writes of a transaction wait in the session until it is committed
+    fn write(&mut self, session: &mut Session, batch: WriteBatch) -> Result<()> {
+        match &mut session.transaction {
+            Some(writes) => {
+                for record in batch.records {
+                    writes.insert(record.key.clone(), record);
+                }
+                Ok(())
+            }
+            None => self.storage_mut(session)?.write_batch(batch),
+        }
+    }
+
+    // the live record of the key, the uncommitted writes of the session come first
+    fn lookup(
+        &self,
+        session: &Session,
+        key: &[u8],
+        snapshot: Option<&Snapshot>,
+    ) -> Result<Option<Record>> {
+        match session.transaction.as_ref().and_then(|w| w.get(key)) {
+            Some(record) => Ok(record.is_live(now()).then(|| record.clone())),
+            None => self.storage(session)?.lookup(key, snapshot),
+        }
+    }
+
+    // a session reads its own uncommitted writes first, then its snapshot if it holds one
+    fn get(&self, session: &Session, key: &[u8]) -> Result<Response> {
+        if let Some(record) = session.transaction.as_ref().and_then(|w| w.get(key)) {
+            return match record.is_live(now()) {
+                true => Ok(Response::Record {
+                    key: key.to_vec(),
+                    value: record.value.clone(),
+                }),
+                false => Err(Error {
+                    kind: ErrorKind::Key,
+                }),
+            };
+        }
+        let storage = self.storage(session)?;
+        match &session.snapshot {
+            Some(snapshot) => storage.get_at(key, snapshot),
+            None => storage.get(key),
+        }
+    }
+
+    fn scan(
+        &self,
+        session: &Session,
+        start: &[u8],
+        end: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Response> {
+        let storage = self.storage(session)?;
+        let snapshot = session.snapshot.as_ref();
+        let bounds = (Bound::Included(start), Bound::Excluded(end));
+        let writes = match &session.transaction {
+            Some(writes) if start < end && writes.range::<[u8], _>(bounds).next().is_some() => {
+                writes
+            }
+            _ => return storage.scan(start, end, limit, snapshot),
+        };
+        // the uncommitted writes are laid over the whole range, then the limit is applied
+        let mut pairs = match storage.scan(start, end, None, snapshot)? {
+            Response::Records { records } => records.into_iter().collect::<BTreeMap<_, _>>(),
+            response => return Ok(response),
+        };
+        let now = now();
+        for (key, record) in writes.range::<[u8], _>(bounds) {
+            match record.is_live(now) {
+                true => pairs.insert(key.clone(), record.value.clone()),
+                false => pairs.remove(key),
+            };
+        }
+        Ok(Response::Records {
+            records: pairs
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .collect(),
+        })
+    }
+
+    /// apply the puts and deletes of the batch at once, in the keyspace of `execute`
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.keyspaces
+            .get_mut(self.session.keyspace())?
+            .write_batch(batch)
+    }
+
+    /// compact the levels which outgrew their limits and reclaim the expired keys nobody reads,
+    /// in every keyspace, see `Storage::sweep`
+    pub fn sweep(&mut self) -> Result<()> {
+        self.keyspaces.storages_mut().try_for_each(Storage::sweep)
+    }
+
+    /// merge the segment files of the keyspace of `execute`, dropping the overwritten values
+    pub fn merge(&mut self) -> Result<Response> {
+        self.keyspaces.get_mut(self.session.keyspace())?.compact()
+    }
+
+    /// drop every key of the keyspace of `execute` for good, as the `clear` statement does,
+    /// see `Storage::truncate`
+    pub fn truncate(&mut self) -> Result<()> {
+        self.keyspaces.get_mut(self.session.keyspace())?.truncate()
+    }
+}
+
+// the statements which move the data only apply to the default keyspace
+fn in_default(session: &Session) -> Result<()> {
+    match session.keyspace() {
+        keyspace::DEFAULT => Ok(()),
+        _ => Err(Error {
+            kind: ErrorKind::Keyspace,
+        }),
+    }
 }
 
 #[cfg(test)]
@@ -58,8 +374,8 @@ mod test {
     use super::*;
     #[tokio::test]
     async fn test() {
-        let dir = "/tmp/dpdb-test-storage";
-        std::fs::create_dir(dir).unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
         {
             let mut executor = Executor::new(dir).await.unwrap();
             let _ = executor.execute("attach bench.db");
@@ -74,6 +390,181 @@ mod test {
             let report = executor.execute("get needle");
             assert!(matches!(report.response, Response::Record { .. }));
         }
-        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut executor = Executor::new(dir).await.unwrap();
+        let mut reader = Session::default();
+        executor.execute("set needle hay");
+        executor.execute_in(&mut reader, "snapshot");
+        executor.execute("set needle straw");
+        let report = executor.execute_in(&mut reader, "get needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"hay"));
+        let report = executor.execute("get needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"straw"));
+        executor.execute_in(&mut reader, "release");
+        let report = executor.execute_in(&mut reader, "get needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"straw"));
+    }
+
+    #[tokio::test]
+    async fn transaction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut executor = Executor::new(dir).await.unwrap();
+        let mut writer = Session::default();
+        executor.execute("set needle hay");
+        executor.execute("set pin cushion");
+        executor.execute_in(&mut writer, "begin");
+        executor.execute_in(&mut writer, "set needle straw");
+        executor.execute_in(&mut writer, "del pin");
+        executor.execute_in(&mut writer, "mset thread cotton yarn wool");
+        // the writes are seen by their own session only
+        let report = executor.execute_in(&mut writer, "get needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"straw"));
+        let report = executor.execute_in(&mut writer, "scan a z");
+        assert!(matches!(report.response, Response::Records { records } if records.len() == 3));
+        let report = executor.execute("get needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"hay"));
+        executor.execute_in(&mut writer, "commit");
+        let report = executor.execute("scan a z");
+        assert!(matches!(report.response, Response::Records { records } if records.len() == 3));
+        assert!(matches!(
+            executor.execute("get pin").response,
+            Response::Error { .. }
+        ));
+
+        executor.execute_in(&mut writer, "begin");
+        executor.execute_in(&mut writer, "set needle thread");
+        executor.execute_in(&mut writer, "rollback");
+        let report = executor.execute("get needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"straw"));
+        let report = executor.execute_in(&mut writer, "commit");
+        assert!(matches!(report.response, Response::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut executor = Executor::new(dir).await.unwrap();
+        executor.execute("set gone soon ex 0");
+        assert!(matches!(
+            executor.execute("get gone").response,
+            Response::Error { .. }
+        ));
+        executor.execute("set needle hay ex 100");
+        let report = executor.execute("ttl needle");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"100"));
+        executor.execute("set pin cushion");
+        let report = executor.execute("ttl pin");
+        assert!(matches!(report.response, Response::Record { value, .. } if value == b"-1"));
+        executor.execute("expire pin 0");
+        assert!(matches!(
+            executor.execute("get pin").response,
+            Response::Error { .. }
+        ));
+        assert!(matches!(
+            executor.execute("expire pin 10").response,
+            Response::Error { .. }
+        ));
+        executor.sweep().unwrap();
+    }
+
+    #[tokio::test]
+    async fn keyspaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let value = |report: Report| match report.response {
+            Response::Record { value, .. } => Some(value),
+            _ => None,
+        };
+        {
+            let mut executor = Executor::new(dir).await.unwrap();
+            let mut orders = Session::default();
+            executor.execute("set needle hay");
+            assert!(matches!(
+                executor.execute_in(&mut orders, "use orders").response,
+                Response::Error { .. }
+            ));
+            executor.execute("create keyspace orders");
+            executor.execute("create keyspace stale");
+            for name in ["orders", "bad.name", "default"] {
+                let report = executor.execute(&format!("create keyspace {}", name));
+                assert!(matches!(report.response, Response::Error { .. }));
+            }
+            executor.execute_in(&mut orders, "use orders");
+            executor.execute_in(&mut orders, "set needle straw");
+            // every keyspace keeps its own keys
+            assert_eq!(value(executor.execute("get needle")), Some(b"hay".to_vec()));
+            let report = executor.execute_in(&mut orders, "get needle");
+            assert_eq!(value(report), Some(b"straw".to_vec()));
+            let report = executor.execute_in(&mut orders, "list keyspaces");
+            assert!(
+                matches!(report.response, Response::Records { records } if records == vec![
+                    (b"default".to_vec(), vec![]),
+                    (b"orders".to_vec(), b"current".to_vec()),
+                    (b"stale".to_vec(), vec![]),
+                ])
+            );
+            executor.execute_in(&mut orders, "snapshot");
+            assert!(matches!(
+                executor.execute_in(&mut orders, "use default").response,
+                Response::Error { .. }
+            ));
+            executor.execute_in(&mut orders, "release");
+            assert!(matches!(
+                executor.execute_in(&mut orders, "mv-to /tmp").response,
+                Response::Error { .. }
+            ));
+            // the keyspaces would stay behind, nor can the default one go into a subdirectory
+            let other = tempfile::tempdir().unwrap();
+            for target in [other.path(), &tmp.path().join("orders")] {
+                let report = executor.execute(&format!("attach-to {}", target.display()));
+                assert!(matches!(report.response, Response::Error { .. }));
+            }
+            executor.execute("drop keyspace stale");
+            assert!(!tmp.path().join("stale").exists());
+            assert!(matches!(
+                executor.execute("drop keyspace default").response,
+                Response::Error { .. }
+            ));
+        }
+        // a crash while dropping leaves the renamed directory behind
+        std::fs::create_dir(tmp.path().join("gone.dropped")).unwrap();
+        std::fs::write(tmp.path().join("gone.dropped").join("MANIFEST"), b"").unwrap();
+        let mut executor = Executor::new(dir).await.unwrap();
+        assert!(!tmp.path().join("gone.dropped").exists());
+        let mut orders = Session::default();
+        executor.execute_in(&mut orders, "use orders");
+        let report = executor.execute_in(&mut orders, "get needle");
+        assert_eq!(value(report), Some(b"straw".to_vec()));
+        assert!(matches!(
+            executor.execute("use stale").response,
+            Response::Error { .. }
+        ));
+        executor.execute("drop keyspace orders");
+        std::fs::create_dir(tmp.path().join("inner")).unwrap();
+        let report = executor.execute(&format!("mv-to {}", tmp.path().join("inner").display()));
+        assert!(matches!(report.response, Response::Error { .. }));
+        assert_eq!(value(executor.execute("get needle")), Some(b"hay".to_vec()));
+
+        // the keyspaces are created next to wherever the default one moved
+        let (old, new) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut executor = Executor::new(old.path().to_str().unwrap()).await.unwrap();
+        let report = executor.execute(&format!("mv-to {}", new.path().display()));
+        assert!(matches!(report.response, Response::Ok));
+        executor.execute("create keyspace orders");
+        assert!(new.path().join("orders").join("MANIFEST").exists());
+        assert!(!old.path().join("orders").exists());
+
+        let mut executor = Executor::in_memory(Options::default()).await.unwrap();
+        executor.execute("create keyspace orders");
+        executor.execute("set needle hay");
+        executor.execute_in(&mut orders, "use orders");
+        assert!(value(executor.execute_in(&mut orders, "get needle")).is_none());
     }
 }
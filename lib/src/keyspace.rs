@@ -0,0 +1,198 @@
+use crate::storage::{sync_dir, MemoryFileSystem, Options, Storage};
+use crate::{Error, ErrorKind, Result};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// the keyspace of a session until `use` picks another, it can't be dropped
+pub const DEFAULT: &str = "default";
+
+// what a dropped keyspace is renamed to before it is removed, it is no keyspace name
+const DROPPED: &str = "dropped";
+
+/// the keyspaces of a server, each an isolated storage with its own files:
+/// the default one lives in the data directory itself, the others in its subdirectories named after them.
+/// A subdirectory is a keyspace once it holds a `MANIFEST`
+pub struct Keyspaces {
+    // the named keyspaces are kept in memory, the directory of the default one is no data directory
+    in_memory: bool,
+    options: Options,
+    storages: BTreeMap<String, Storage>,
+}
+
+fn keyspace_error() -> Error {
+    Error {
+        kind: ErrorKind::Keyspace,
+    }
+}
+
+// a name is a directory name too, so it can't be taken for a segment file, a temporary or a dropped keyspace
+fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Keyspaces {
+    /// the keyspaces found in the data directory, the ones a crash left half dropped are removed
+    pub fn open(root: &str, options: Options) -> Result<Self> {
+        let mut keyspaces = Keyspaces {
+            in_memory: false,
+            storages: BTreeMap::new(),
+            options,
+        };
+        let storage = Storage::new(root, keyspaces.options.clone())?;
+        keyspaces.storages.insert(DEFAULT.to_string(), storage);
+        for entry in fs::read_dir(root)? {
+            let dir = entry?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            if dir.extension().is_some_and(|ext| ext == DROPPED) {
+                warn!("removing dropped keyspace {}", dir.display());
+                fs::remove_dir_all(&dir)?;
+                continue;
+            }
+            let name = match dir.file_name().and_then(|name| name.to_str()) {
+                Some(name) if is_valid(name) && name != DEFAULT => name.to_string(),
+                _ => continue,
+            };
+            if dir.join("MANIFEST").exists() {
+                let storage = keyspaces.open_storage(&name)?;
+                keyspaces.storages.insert(name, storage);
+            }
+        }
+        info!("opened {} keyspaces in {}", keyspaces.storages.len(), root);
+        Ok(keyspaces)
+    }
+
+    /// a keyspace of its own `MemoryFileSystem` for every name
+    pub fn in_memory(options: Options) -> Result<Self> {
+        let mut keyspaces = Keyspaces {
+            in_memory: true,
+            storages: BTreeMap::new(),
+            options,
+        };
+        let storage = keyspaces.open_storage(DEFAULT)?;
+        keyspaces.storages.insert(DEFAULT.to_string(), storage);
+        Ok(keyspaces)
+    }
+
+    // the data directory, where the default keyspace is, None when the data is kept in memory
+    fn root(&self) -> Option<&Path> {
+        match self.in_memory {
+            true => None,
+            false => self.storages.get(DEFAULT).map(Storage::dir),
+        }
+    }
+
+    // the storage of a named keyspace, the one left in its directory if there is one
+    fn open_storage(&self, name: &str) -> Result<Storage> {
+        let root = match self.root() {
+            Some(root) => root,
+            None => {
+                let fs = MemoryFileSystem::new(&self.options);
+                return Storage::with_fs(Box::new(fs), self.options.clone());
+            }
+        };
+        let dir = root.join(name);
+        fs::create_dir_all(&dir)?;
+        let storage = Storage::new(&dir.to_string_lossy(), self.options.clone())?;
+        // the manifest is there, make the directory holding it durable as well
+        sync_dir(root)?;
+        Ok(storage)
+    }
+
+    /// move the default keyspace to `dir`, see `Storage::move_to`
+    pub fn move_to(&mut self, dir: &str) -> Result<()> {
+        self.check_move(dir)?;
+        self.get_mut(DEFAULT)?.move_to(dir)
+    }
+
+    /// go on with the storage in `dir` as the default keyspace, see `Storage::attach_to`
+    pub fn attach_to(&mut self, dir: &str) -> Result<()> {
+        self.check_move(dir)?;
+        self.get_mut(DEFAULT)?.attach_to(dir)
+    }
+
+    // the named keyspaces would stay behind in the subdirectories, where no restart looks for them,
+    // so the default keyspace only goes when it is the only one.
+    // Nor does it go into a subdirectory, which is where a named keyspace is, or is found by the next start
+    fn check_move(&self, dir: &str) -> Result<()> {
+        if self.storages.len() > 1 {
+            return Err(keyspace_error());
+        }
+        if let (Some(root), Ok(dir)) = (self.root(), Path::new(dir).canonicalize()) {
+            let root = root.canonicalize()?;
+            if dir != root && dir.starts_with(&root) {
+                return Err(Error {
+                    kind: ErrorKind::File,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Storage> {
+        self.storages.get(name).ok_or_else(keyspace_error)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut Storage> {
+        self.storages.get_mut(name).ok_or_else(keyspace_error)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.storages.keys().map(String::as_str)
+    }
+
+    pub fn storages_mut(&mut self) -> impl Iterator<Item = &mut Storage> {
+        self.storages.values_mut()
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<()> {
+        if !is_valid(name) || self.storages.contains_key(name) {
+            return Err(keyspace_error());
+        }
+        let storage = self.open_storage(name)?;
+        self.storages.insert(name.to_string(), storage);
+        Ok(())
+    }
+
+    /// remove the keyspace along with its files. The directory is renamed first,
+    /// so a crash in the middle leaves no keyspace behind, only a directory removed at the next start
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT {
+            return Err(keyspace_error());
+        }
+        // the flush in flight is done before the files go
+        self.storages.remove(name).ok_or_else(keyspace_error)?;
+        let root = match self.root() {
+            Some(root) => root.to_path_buf(),
+            None => return Ok(()),
+        };
+        let dropped = root.join(format!("{}.{}", name, DROPPED));
+        if let Err(e) = fs::rename(root.join(name), &dropped) {
+            // the keyspace is still where it was
+            let storage = self.open_storage(name)?;
+            self.storages.insert(name.to_string(), storage);
+            return Err(e.into());
+        }
+        sync_dir(&root)?;
+        fs::remove_dir_all(dropped)?;
+        Ok(())
+    }
+}
+
+// the storage is the default keyspace, the ones created next to it are kept in memory
+impl From<Storage> for Keyspaces {
+    fn from(storage: Storage) -> Self {
+        Keyspaces {
+            in_memory: true,
+            options: Options::default(),
+            storages: BTreeMap::from([(DEFAULT.to_string(), storage)]),
+        }
+    }
+}
@@ -1,11 +1,15 @@
 mod error;
 pub mod executor;
+mod keyspace;
 mod parser;
 mod statement;
 mod storage;
 mod utils;
 mod response;
 mod report;
+mod session;
 mod test;
 
 pub use error::*;
+pub use session::Session;
+pub use storage::{Builder, Compression, Options, ReadMode, SyncPolicy, WriteBatch};
@@ -2,10 +2,11 @@ extern crate nom;
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag},
-    character::complete::multispace0,
-    combinator::eof,
+    character::complete::{digit1, multispace0},
+    combinator::{eof, map_res, opt},
     error::ParseError,
-    sequence::delimited,
+    multi::many1,
+    sequence::{delimited, pair, preceded},
     IResult,
 };
 
@@ -24,9 +25,25 @@ pub(crate) fn parse_sql(input: &str) -> IResult<&str, Statement> {
     alt((
         parse_clear,
         parse_set,
+        parse_mset,
         parse_get,
+        parse_del,
+        parse_expire,
+        parse_ttl,
+        parse_scan,
         parse_move_file,
         parse_attach_file,
+        parse_compact,
+        parse_stats,
+        parse_snapshot,
+        parse_release,
+        parse_begin,
+        parse_commit,
+        parse_rollback,
+        parse_create_keyspace,
+        parse_drop_keyspace,
+        parse_use,
+        parse_list_keyspaces,
     ))(input)
 }
 
@@ -39,6 +56,121 @@ fn parse_clear(input: &str) -> IResult<&str, Statement> {
             verb: Keyword::Clear,
             key: String::default(),
             value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_stats(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("stats"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Stats,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_snapshot(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("snapshot"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Snapshot,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_release(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("release"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Release,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_begin(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("begin"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Begin,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_commit(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("commit"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Commit,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_rollback(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("rollback"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Rollback,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_compact(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("compact"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Compact,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
         },
     ))
 }
@@ -47,6 +179,7 @@ fn parse_set(input: &str) -> IResult<&str, Statement> {
     let (input, _) = ws(tag("set"))(input)?;
     let (input, key) = ws(literal)(input)?;
     let (input, value) = ws(literal)(input)?;
+    let (input, ttl) = opt(preceded(ws(tag("ex")), ws(seconds)))(input)?;
     let (input, _) = eof(input)?;
 
     Ok((
@@ -55,6 +188,67 @@ fn parse_set(input: &str) -> IResult<&str, Statement> {
             verb: Keyword::Set,
             key: key.to_string(),
             value: value.to_string(),
+            limit: None,
+            pairs: vec![],
+            ttl,
+        },
+    ))
+}
+
+fn parse_mset(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("mset"))(input)?;
+    let (input, pairs) = many1(pair(ws(literal), ws(literal)))(input)?;
+    let (input, _) = eof(input)?;
+
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::MultiSet,
+            key: Default::default(),
+            value: Default::default(),
+            limit: None,
+            pairs: pairs
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_expire(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("expire"))(input)?;
+    let (input, key) = ws(literal)(input)?;
+    let (input, ttl) = ws(seconds)(input)?;
+    let (input, _) = eof(input)?;
+
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Expire,
+            key: key.to_string(),
+            value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: Some(ttl),
+        },
+    ))
+}
+
+fn parse_ttl(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("ttl"))(input)?;
+    let (input, key) = ws(literal)(input)?;
+    let (input, _) = eof(input)?;
+
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Ttl,
+            key: key.to_string(),
+            value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
         },
     ))
 }
@@ -70,6 +264,47 @@ fn parse_get(input: &str) -> IResult<&str, Statement> {
             verb: Keyword::Get,
             key: key.to_string(),
             value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_del(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("del"))(input)?;
+    let (input, key) = ws(literal)(input)?;
+    let (input, _) = eof(input)?;
+
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Delete,
+            key: key.to_string(),
+            value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_scan(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("scan"))(input)?;
+    let (input, start) = ws(literal)(input)?;
+    let (input, end) = ws(literal)(input)?;
+    let (input, limit) = opt(ws(number))(input)?;
+    let (input, _) = eof(input)?;
+
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Scan,
+            key: start.to_string(),
+            value: end.to_string(),
+            limit,
+            pairs: vec![],
+            ttl: None,
         },
     ))
 }
@@ -85,6 +320,9 @@ fn parse_attach_file(input: &str) -> IResult<&str, Statement> {
             verb: Keyword::AttachFile,
             key: file.to_string(),
             value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
         },
     ))
 }
@@ -100,6 +338,79 @@ fn parse_move_file(input: &str) -> IResult<&str, Statement> {
             verb: Keyword::MoveFile,
             key: file.to_string(),
             value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_create_keyspace(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("create"))(input)?;
+    let (input, _) = ws(tag("keyspace"))(input)?;
+    let (input, name) = ws(literal)(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::CreateKeyspace,
+            key: name.to_string(),
+            value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_drop_keyspace(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("drop"))(input)?;
+    let (input, _) = ws(tag("keyspace"))(input)?;
+    let (input, name) = ws(literal)(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::DropKeyspace,
+            key: name.to_string(),
+            value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_use(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("use"))(input)?;
+    let (input, name) = ws(literal)(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::Use,
+            key: name.to_string(),
+            value: Default::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
+        },
+    ))
+}
+
+fn parse_list_keyspaces(input: &str) -> IResult<&str, Statement> {
+    let (input, _) = ws(tag("list"))(input)?;
+    let (input, _) = ws(tag("keyspaces"))(input)?;
+    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Statement {
+            verb: Keyword::ListKeyspaces,
+            key: String::default(),
+            value: String::default(),
+            limit: None,
+            pairs: vec![],
+            ttl: None,
         },
     ))
 }
@@ -109,6 +420,16 @@ pub fn literal(input: &str) -> IResult<&str, &str> {
     is_not(" \t\r\n")(input)
 }
 
+/// match a non-negative integer
+pub fn number(input: &str) -> IResult<&str, usize> {
+    map_res(digit1, str::parse)(input)
+}
+
+/// match a number of seconds
+pub fn seconds(input: &str) -> IResult<&str, u64> {
+    map_res(digit1, str::parse)(input)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -117,5 +438,28 @@ mod test {
         let (_, output) = parse_sql("set a 2").unwrap();
         assert_eq!(output.key, "a");
         assert_eq!(output.value, "2");
+        let (_, output) = parse_sql("scan a z 10").unwrap();
+        assert_eq!(output.key, "a");
+        assert_eq!(output.value, "z");
+        assert_eq!(output.limit, Some(10));
+        let (_, output) = parse_sql("mset a 1 b 2").unwrap();
+        assert_eq!(output.pairs.len(), 2);
+        assert_eq!(output.pairs[1], ("b".to_string(), "2".to_string()));
+        assert!(parse_sql("mset a 1 b").is_err());
+        let (_, output) = parse_sql("set a 2 ex 10").unwrap();
+        assert_eq!((output.value.as_str(), output.ttl), ("2", Some(10)));
+        let (_, output) = parse_sql("expire a 10").unwrap();
+        assert_eq!(output.ttl, Some(10));
+        assert!(parse_sql("set a 2 ex").is_err());
+        let (_, output) = parse_sql("create keyspace orders").unwrap();
+        assert!(matches!(output.verb, Keyword::CreateKeyspace));
+        assert_eq!(output.key, "orders");
+        let (_, output) = parse_sql("use orders").unwrap();
+        assert!(matches!(output.verb, Keyword::Use));
+        assert!(matches!(
+            parse_sql("list keyspaces").unwrap().1.verb,
+            Keyword::ListKeyspaces
+        ));
+        assert!(parse_sql("drop keyspace").is_err());
     }
 }
@@ -19,6 +19,17 @@ impl Report {
                     std::str::from_utf8(key)?,
                     std::str::from_utf8(value)?
                 ),
+                Response::Records { ref records } => records
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok(format!(
+                            "{}: {}",
+                            std::str::from_utf8(key)?,
+                            std::str::from_utf8(value)?
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .join("\r\n"),
                 Response::Error { ref msg } => format!("error: {}", msg),
                 Response::Ok => "Ok".to_string(),
             },
@@ -1,5 +1,6 @@
 pub enum Response {
     Record { key: Vec<u8>, value: Vec<u8> },
+    Records { records: Vec<(Vec<u8>, Vec<u8>)> },
     Ok,
     Error { msg: String },
 }
\ No newline at end of file
@@ -0,0 +1,21 @@
+use crate::keyspace;
+use crate::storage::{Record, Snapshot};
+use std::collections::BTreeMap;
+
+/// the state a client keeps from one statement to the next,
+/// a server holds one for every connection
+#[derive(Default)]
+pub struct Session {
+    // the reads of the session go through it until it is released
+    pub(crate) snapshot: Option<Snapshot>,
+    // the writes buffered since `begin`, the last one of each key, `commit` applies them at once
+    pub(crate) transaction: Option<BTreeMap<Vec<u8>, Record>>,
+    // the keyspace of the statements, picked by `use`, the default one until then
+    pub(crate) keyspace: Option<String>,
+}
+
+impl Session {
+    pub(crate) fn keyspace(&self) -> &str {
+        self.keyspace.as_deref().unwrap_or(keyspace::DEFAULT)
+    }
+}
@@ -4,7 +4,23 @@ pub(crate) enum Keyword {
     MoveFile,
     AttachFile,
     Set,
+    MultiSet,
     Get,
+    Delete,
+    Scan,
+    Compact,
+    Stats,
+    Snapshot,
+    Release,
+    Begin,
+    Commit,
+    Rollback,
+    Expire,
+    Ttl,
+    CreateKeyspace,
+    DropKeyspace,
+    Use,
+    ListKeyspaces,
 }
 
 #[derive(Debug)]
@@ -12,4 +28,9 @@ pub(crate) struct Statement {
     pub(crate) verb: Keyword,
     pub(crate) key: String,
     pub(crate) value: String,
+    pub(crate) limit: Option<usize>,
+    // the key and value pairs of mset
+    pub(crate) pairs: Vec<(String, String)>,
+    // the seconds to live of `set ... ex` and of `expire`
+    pub(crate) ttl: Option<u64>,
 }
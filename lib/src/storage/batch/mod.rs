@@ -0,0 +1,75 @@
+use super::data_format::Record;
+use crate::utils::now;
+use std::time::Duration;
+
+/// puts and deletes which are applied at once, see `Storage::write_batch`:
+///
+/// ```ignore
+/// let mut batch = WriteBatch::new();
+/// batch.put(b"needle", b"hay").delete(b"pin");
+/// executor.write_batch(batch)?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    // in the order they are added, a later write of a key wins,
+    // the sequence numbers are handed out when the batch is written
+    pub(crate) records: Vec<Record>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.push(Record::new(key.to_vec(), 0, Some(value.to_vec())))
+    }
+
+    /// the value expires once `ttl` has passed from now
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> &mut Self {
+        let mut record = Record::new(key.to_vec(), 0, Some(value.to_vec()));
+        record.expires = Some(now().saturating_add(ttl.as_millis() as u64));
+        self.push(record)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.push(Record::new(key.to_vec(), 0, None))
+    }
+
+    pub(crate) fn push(&mut self, record: Record) -> &mut Self {
+        self.records.push(record);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+/// a batch of puts(Some) and deletes(None)
+impl FromIterator<(Vec<u8>, Option<Vec<u8>>)> for WriteBatch {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>>(iter: I) -> Self {
+        WriteBatch {
+            records: iter
+                .into_iter()
+                .map(|(key, value)| Record::new(key, 0, value))
+                .collect(),
+        }
+    }
+}
+
+impl FromIterator<Record> for WriteBatch {
+    fn from_iter<I: IntoIterator<Item = Record>>(iter: I) -> Self {
+        WriteBatch {
+            records: iter.into_iter().collect(),
+        }
+    }
+}
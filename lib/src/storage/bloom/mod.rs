@@ -0,0 +1,96 @@
+use crate::{Error, ErrorKind, Result};
+use std::f64::consts::LN_2;
+
+// the filter is persisted, so the hash must not change between builds,
+// which rules out the std hashers: FNV-1a, followed by a finalizer to spread the bits
+pub fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in key {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+/// a Bloom filter over the keys of a segment,
+/// `may_contain` never says no to a key that was added
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// size the filter for `keys` keys at the given false-positive rate
+    pub fn new(keys: usize, false_positive_rate: f64) -> Self {
+        let keys = keys.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 1.0);
+        let bits = (-keys * rate.ln() / (LN_2 * LN_2)).ceil().max(8.0);
+        let hashes = (bits / keys * LN_2).round().clamp(1.0, 30.0);
+        BloomFilter {
+            bits: vec![0u8; (bits as usize).div_ceil(8)],
+            hashes: hashes as u32,
+        }
+    }
+
+    // double hashing: the i-th probe is h1 + i * h2
+    fn probes(&self, h: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (h as u32, (h >> 32) as u32);
+        let bits = self.bits.len() as u64 * 8;
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) as u64 % bits) as usize)
+    }
+
+    /// the writer of a segment only learns the number of keys at the end,
+    /// so it keeps the hashes rather than the keys
+    pub fn add_hash(&mut self, h: u64) {
+        for bit in self.probes(h).collect::<Vec<_>>() {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// the layout of a filter is: number of hashes|bits
+    pub fn encode(&self) -> Vec<u8> {
+        [&self.hashes.to_be_bytes()[..], &self.bits].concat()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() <= 4 {
+            return Err(Error {
+                kind: ErrorKind::File,
+            });
+        }
+        Ok(BloomFilter {
+            hashes: u32::from_be_bytes(buf[..4].try_into()?),
+            bits: buf[4..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bloom_filter() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.add_hash(hash(format!("key{}", i).as_bytes()));
+        }
+        let filter = BloomFilter::decode(&filter.encode()).unwrap();
+        for i in 0..1000 {
+            assert!(filter.may_contain(format!("key{}", i).as_bytes()));
+        }
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("missing{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,154 @@
+use super::data_format::Record;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+/// a least-recently-used cache bounded by the total charge of its entries
+pub struct Lru<K, V> {
+    capacity: usize,
+    used: usize,
+    // bumped on every access, the smallest tick is the least recently used entry
+    tick: u64,
+    entries: HashMap<K, (V, usize, u64)>,
+    order: BTreeMap<u64, K>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let tick = self.tick + 1;
+        match self.entries.get_mut(key) {
+            Some((value, _, last)) => {
+                self.order.remove(last);
+                self.order.insert(tick, key.clone());
+                *last = tick;
+                self.tick = tick;
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// an entry charged more than the whole capacity is not kept
+    pub fn insert(&mut self, key: K, value: V, charge: usize) {
+        if charge > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.used + charge > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    if let Some((_, charge, _)) = self.entries.remove(&oldest) {
+                        self.used -= charge;
+                    }
+                }
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, charge, self.tick));
+        self.used += charge;
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((_, charge, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.used -= charge;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// drop every entry, the counters are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.used = 0;
+    }
+
+    /// the total charge of the entries
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+/// a block of a segment, by the id of the segment and the offset of the block
+pub type BlockKey = (u64, u64);
+
+/// the decoded blocks of the segments, shared by all the lookups,
+/// the open files are up to the `FS`
+pub struct Cache {
+    blocks: Mutex<Lru<BlockKey, Arc<Vec<Record>>>>,
+}
+
+impl Cache {
+    /// `block_bytes` bounds the size of the cached blocks
+    pub fn new(block_bytes: usize) -> Self {
+        Cache {
+            blocks: Mutex::new(Lru::new(block_bytes)),
+        }
+    }
+
+    pub fn block(&self, key: &BlockKey) -> Option<Arc<Vec<Record>>> {
+        self.blocks.lock().unwrap().get(key)
+    }
+
+    pub fn insert_block(&self, key: BlockKey, records: Arc<Vec<Record>>, size: usize) {
+        self.blocks.lock().unwrap().insert(key, records, size);
+    }
+
+    /// the counters, as name and value pairs
+    pub fn stats(&self) -> Vec<(String, u64)> {
+        let blocks = self.blocks.lock().unwrap();
+        vec![
+            ("block_cache_hits".to_string(), blocks.hits),
+            ("block_cache_misses".to_string(), blocks.misses),
+            ("block_cache_blocks".to_string(), blocks.len() as u64),
+            ("block_cache_bytes".to_string(), blocks.used() as u64),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut lru = Lru::new(3);
+        lru.insert("a", 1, 1);
+        lru.insert("b", 2, 1);
+        lru.insert("c", 3, 1);
+        assert_eq!(lru.get(&"a"), Some(1));
+        // b is the least recently used
+        lru.insert("d", 4, 1);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(1));
+        lru.insert("e", 5, 2);
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.used(), 3);
+        lru.insert("f", 6, 4);
+        assert_eq!(lru.get(&"f"), None);
+        assert_eq!((lru.hits, lru.misses), (2, 2));
+    }
+}
@@ -0,0 +1,214 @@
+use super::data_format::Record;
+use super::options::Options;
+use super::segment::Segment;
+use crate::{Error, Result};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+// the head of a source waiting in the heap
+struct Head {
+    record: Record,
+    source: usize,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    // BinaryHeap is a max-heap, so the order is reversed:
+    // the smallest key comes out first, and for the same key the latest write wins,
+    // or the youngest source, should the sequence numbers tie
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .record
+            .key
+            .cmp(&self.record.key)
+            .then(self.record.seq.cmp(&other.record.seq))
+            .then(self.source.cmp(&other.source))
+    }
+}
+
+/// k-way merge of sorted sources, the sources are ordered from the oldest to the youngest,
+/// when a key appears in more than one source, only the latest version is yielded.
+/// A source that fails ends the merge, the error is yielded as the last item
+pub struct Merger<I: Iterator<Item = Result<Record>>> {
+    sources: Vec<I>,
+    heap: BinaryHeap<Head>,
+    error: Option<Error>,
+}
+
+impl<I: Iterator<Item = Result<Record>>> Merger<I> {
+    pub fn new(sources: Vec<I>) -> Self {
+        let mut merger = Merger {
+            sources,
+            heap: BinaryHeap::new(),
+            error: None,
+        };
+        for source in 0..merger.sources.len() {
+            merger.advance(source);
+        }
+        merger
+    }
+
+    fn advance(&mut self, source: usize) {
+        match self.sources[source].next() {
+            Some(Ok(record)) => self.heap.push(Head { record, source }),
+            // the first failure is the one worth reporting
+            Some(Err(e)) if self.error.is_none() => self.error = Some(e),
+            _ => {}
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Record>>> Iterator for Merger<I> {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        // the rest of a failed source is unknown, nothing after it can be trusted
+        if let Some(e) = self.error.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+        let head = self.heap.pop()?;
+        self.advance(head.source);
+        // the older versions of the same key are shadowed
+        while let Some(older) = self.heap.peek() {
+            if older.record.key != head.record.key {
+                break;
+            }
+            let older = self.heap.pop().unwrap();
+            self.advance(older.source);
+        }
+        Some(Ok(head.record))
+    }
+}
+
+/// the segments of a compaction, by their positions in their levels:
+/// `inputs` of `level` are merged with the `overlapped` segments of the next level
+pub struct Compaction {
+    pub level: usize,
+    pub inputs: Vec<usize>,
+    pub overlapped: Vec<usize>,
+}
+
+/// the segments of level 0 overlap each other, the deeper levels are split into disjoint key ranges,
+/// a level which outgrows its limit is the next to be compacted, the most outgrown first
+pub fn pick(levels: &[Vec<Arc<Segment>>], options: &Options) -> Option<Compaction> {
+    let score = |level: usize| -> f64 {
+        let segments = &levels[level];
+        if level == 0 {
+            return segments.len() as f64 / options.compaction_trigger as f64;
+        }
+        let size: u64 = segments.iter().map(|segment| segment.size).sum();
+        size as f64 / options.level_size(level) as f64
+    };
+    let level = (0..levels.len())
+        .filter(|&level| score(level) >= 1.0)
+        .max_by(|&a, &b| score(a).total_cmp(&score(b)))?;
+    // the oldest segment goes first
+    select(levels, level, 0)
+}
+
+/// the compaction of the first segment holding a value which has expired by `now`,
+/// its output drops the value, or keeps a tombstone in its place
+pub fn expired(levels: &[Vec<Arc<Segment>>], now: u64) -> Option<Compaction> {
+    levels.iter().enumerate().find_map(|(level, segments)| {
+        let expired =
+            |segment: &Arc<Segment>| segment.footer.expires != 0 && segment.footer.expires <= now;
+        select(levels, level, segments.iter().position(expired)?)
+    })
+}
+
+// compact the segment at `first` of the level into the next level
+fn select(levels: &[Vec<Arc<Segment>>], level: usize, first: usize) -> Option<Compaction> {
+    let segments = &levels[level];
+    let inputs = if level == 0 {
+        // the segments of level 0 overlap, leaving one behind could leave an older version above a newer one
+        (0..segments.len()).collect::<Vec<_>>()
+    } else {
+        // along with any segment of its level it overlaps,
+        // which can only be left behind by a crash in the middle of a compaction
+        let first = &segments[first];
+        (0..segments.len())
+            .filter(|&i| segments[i].overlaps(&first.smallest, &first.largest))
+            .collect()
+    };
+    let (smallest, largest) = key_range(inputs.iter().map(|&i| &*segments[i]))?;
+    let overlapped = match levels.get(level + 1) {
+        Some(next) => (0..next.len())
+            .filter(|&i| next[i].overlaps(&smallest, &largest))
+            .collect(),
+        None => vec![],
+    };
+    Some(Compaction {
+        level,
+        inputs,
+        overlapped,
+    })
+}
+
+/// the smallest and the largest key of the segments, None if they are empty
+pub fn key_range<'a>(segments: impl Iterator<Item = &'a Segment>) -> Option<(Vec<u8>, Vec<u8>)> {
+    segments
+        .filter(|segment| segment.footer.records > 0)
+        .fold(None, |range, segment| match range {
+            None => Some((segment.smallest.clone(), segment.largest.clone())),
+            Some((smallest, largest)) => Some((
+                smallest.min(segment.smallest.clone()),
+                largest.max(segment.largest.clone()),
+            )),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(key: &str, seq: u64, value: &str) -> Record {
+        Record::new(
+            key.as_bytes().to_vec(),
+            seq,
+            Some(value.as_bytes().to_vec()),
+        )
+    }
+
+    #[test]
+    fn merge() {
+        let older = vec![
+            record("a", 1, "1"),
+            record("b", 2, "1"),
+            record("d", 3, "1"),
+        ];
+        // a crash can leave a later write of a key in an older source
+        let younger = vec![
+            record("b", 4, "2"),
+            record("c", 5, "2"),
+            record("d", 0, "2"),
+        ];
+        let merged: Vec<(Vec<u8>, Vec<u8>)> =
+            Merger::new(vec![older.into_iter().map(Ok), younger.into_iter().map(Ok)])
+                .map(|r| r.map(|r| (r.key, r.value)))
+                .collect::<Result<_>>()
+                .unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"2".to_vec()),
+                (b"d".to_vec(), b"1".to_vec()),
+            ]
+        );
+    }
+}
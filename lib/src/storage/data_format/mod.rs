@@ -1,12 +1,155 @@
+use crate::{Error, Result};
+use crc32fast::hash as crc32;
 use std::mem::size_of;
+use std::path::Path;
 
-pub fn encode(key: &[u8], value: &[u8]) -> Vec<u8> {
+// the first byte of a record tells a value from a tombstone,
+// or from a value which expires, or from a batch of records, which only the log holds
+pub const VALUE: u8 = 0;
+pub const TOMBSTONE: u8 = 1;
+pub const BATCH: u8 = 2;
+pub const EXPIRING: u8 = 3;
+pub const CRC_SIZE: usize = size_of::<u32>();
+pub const HEADER_SIZE: usize = CRC_SIZE + 1 + size_of::<u64>() + size_of::<usize>() * 2;
+pub const EXPIRY_SIZE: usize = size_of::<u64>();
+
+/// the layout of a record is: crc|kind|seq|len(key)|len(value)|[expiry]|key|value,
+/// the crc covers everything after it, seq is the sequence number of the write,
+/// a deleted key is stored as a tombstone, which has no value,
+/// only a value of kind EXPIRING carries the expiry, in milliseconds since the unix epoch
+pub fn encode(record: &Record) -> Vec<u8> {
+    let (key, seq) = (&record.key[..], record.seq);
+    match (record.tombstone, record.expires) {
+        (true, _) => encode_kind(TOMBSTONE, key, seq, &[], &[]),
+        (false, None) => encode_kind(VALUE, key, seq, &[], &record.value),
+        (false, Some(expires)) => {
+            encode_kind(EXPIRING, key, seq, &expires.to_be_bytes(), &record.value)
+        }
+    }
+}
+
+/// the records of a batch are encoded into the value of a single record,
+/// one checksum covers all of them, so the batch is read back whole or not at all
+pub fn encode_batch(records: &[Record]) -> Vec<u8> {
+    let body = records.iter().flat_map(encode).collect::<Vec<_>>();
+    let seq = records.first().map_or(0, |record| record.seq);
+    encode_kind(BATCH, &[], seq, &[], &body)
+}
+
+fn encode_kind(kind: u8, key: &[u8], seq: u64, expiry: &[u8], value: &[u8]) -> Vec<u8> {
     let key_meta = key.len().to_be_bytes();
     let value_meta = value.len().to_be_bytes();
-    [&key_meta, &value_meta, key, value].concat()
+    let body = [
+        &[kind][..],
+        &seq.to_be_bytes(),
+        &key_meta,
+        &value_meta,
+        expiry,
+        key,
+        value,
+    ]
+    .concat();
+    [&crc32(&body).to_be_bytes()[..], &body].concat()
 }
 
+/// decode the record at the head of the buffer, which sits at `offset` of `file`,
+/// None when the buffer is too short to hold it
+pub fn decode(buf: &[u8], file: &Path, offset: u64) -> Result<Option<Record>> {
+    let header = match buf.get(..HEADER_SIZE) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let seq = u64::from_be_bytes(header[5..13].try_into()?);
+    let klen = usize::from_be_bytes(header[13..21].try_into()?);
+    let kind = header[CRC_SIZE];
+    let start = match kind {
+        EXPIRING => HEADER_SIZE + EXPIRY_SIZE,
+        _ => HEADER_SIZE,
+    };
+    let end = match encoded_size(buf) {
+        Some(end) if end <= buf.len() => end,
+        _ => return Ok(None),
+    };
+    if u32::from_be_bytes(header[..CRC_SIZE].try_into()?) != crc32(&buf[CRC_SIZE..end]) {
+        return Err(Error::corruption(file, offset));
+    }
+    let expires = match kind {
+        EXPIRING => Some(u64::from_be_bytes(buf[HEADER_SIZE..start].try_into()?)),
+        _ => None,
+    };
+    let body = &buf[start..end];
+    Ok(Some(Record {
+        tombstone: kind == TOMBSTONE,
+        expires,
+        seq,
+        klen,
+        vlen: body.len() - klen,
+        key: body[..klen].to_vec(),
+        value: body[klen..].to_vec(),
+    }))
+}
+
+/// the bytes the record at the head of the buffer says it takes, checksum aside,
+/// None when the buffer is too short to hold its header
+pub fn encoded_size(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(..HEADER_SIZE)?;
+    let klen = usize::from_be_bytes(header[13..21].try_into().ok()?);
+    let vlen = usize::from_be_bytes(header[21..29].try_into().ok()?);
+    let start = match header[CRC_SIZE] {
+        EXPIRING => HEADER_SIZE + EXPIRY_SIZE,
+        _ => HEADER_SIZE,
+    };
+    start.checked_add(klen)?.checked_add(vlen)
+}
+
+/// decode the entry at the head of a log, which is a record or a batch of them,
+/// along with the bytes it takes. None when the buffer is too short to hold it
+pub fn decode_entry(buf: &[u8], file: &Path, offset: u64) -> Result<Option<(Vec<Record>, usize)>> {
+    let record = match decode(buf, file, offset)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let size = record.size();
+    if buf[CRC_SIZE] != BATCH {
+        return Ok(Some((vec![record], size)));
+    }
+    let mut records = vec![];
+    let mut pos = 0;
+    while pos < record.value.len() {
+        let at = offset + (HEADER_SIZE + pos) as u64;
+        // the checksum of the batch holds, so a record cut short is not a torn write
+        let inner =
+            decode(&record.value[pos..], file, at)?.ok_or_else(|| Error::corruption(file, at))?;
+        pos += inner.size();
+        records.push(inner);
+    }
+    Ok(Some((records, size)))
+}
+
+/// append the checksum of a block
+pub fn seal(block: &[u8]) -> Vec<u8> {
+    [block, &crc32(block).to_be_bytes()[..]].concat()
+}
+
+/// verify and strip the checksum of a block, which sits at `offset` of `file`
+pub fn unseal<'a>(block: &'a [u8], file: &Path, offset: u64) -> Result<&'a [u8]> {
+    if block.len() < CRC_SIZE {
+        return Err(Error::corruption(file, offset));
+    }
+    let (data, crc) = block.split_at(block.len() - CRC_SIZE);
+    if u32::from_be_bytes(crc.try_into()?) != crc32(data) {
+        return Err(Error::corruption(file, offset));
+    }
+    Ok(data)
+}
+
+#[derive(Clone, Debug)]
 pub struct Record {
+    pub tombstone: bool,
+    // the later a write, the larger its sequence number
+    pub seq: u64,
+    // when the value expires, in milliseconds since the unix epoch
+    pub expires: Option<u64>,
     pub klen: usize,
     pub vlen: usize,
     pub key: Vec<u8>,
@@ -14,7 +157,92 @@ pub struct Record {
 }
 
 impl Record {
+    pub fn new(key: Vec<u8>, seq: u64, value: Option<Vec<u8>>) -> Self {
+        let tombstone = value.is_none();
+        let value = value.unwrap_or_default();
+        Record {
+            tombstone,
+            seq,
+            expires: None,
+            klen: key.len(),
+            vlen: value.len(),
+            key,
+            value,
+        }
+    }
+
+    /// the value, None for a tombstone
+    pub fn value(&self) -> Option<&[u8]> {
+        (!self.tombstone).then_some(&self.value[..])
+    }
+
+    /// the bytes the record takes when it is encoded
     pub fn size(&self) -> usize {
-        self.klen + self.vlen + size_of::<usize>() * 2
+        let expiry = self.expires.map_or(0, |_| EXPIRY_SIZE);
+        self.klen + self.vlen + HEADER_SIZE + expiry
+    }
+
+    /// a value past its expiry reads as a tombstone
+    pub fn is_expired(&self, now: u64) -> bool {
+        !self.tombstone && self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// a value which is neither deleted nor expired
+    pub fn is_live(&self, now: u64) -> bool {
+        !self.tombstone && !self.is_expired(now)
+    }
+
+    /// the record as it is to be written out: an expired value turns into a tombstone,
+    /// which still shadows the older versions of the key
+    pub fn settle(self, now: u64) -> Self {
+        match self.is_expired(now) {
+            true => Record::new(self.key, self.seq, None),
+            false => self,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ErrorKind;
+
+    #[test]
+    fn checksum() {
+        let file = Path::new("data.0");
+        let mut buf = encode(&Record::new(b"needle".to_vec(), 7, Some(b"hay".to_vec())));
+        let record = decode(&buf, file, 0).unwrap().unwrap();
+        assert_eq!(record.value, b"hay");
+        assert_eq!((record.seq, record.expires), (7, None));
+        let mut expiring = record.clone();
+        expiring.expires = Some(42);
+        let decoded = decode(&encode(&expiring), file, 0).unwrap().unwrap();
+        assert_eq!(decoded.expires, Some(42));
+        assert_eq!(decoded.size(), record.size() + EXPIRY_SIZE);
+        assert!(decoded.is_expired(42) && !decoded.is_expired(41));
+        assert!(decode(&buf[..buf.len() - 1], file, 0).unwrap().is_none());
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(matches!(
+            decode(&buf, file, 16),
+            Err(Error {
+                kind: ErrorKind::Corruption { offset: 16, .. }
+            })
+        ));
+
+        let batch = encode_batch(&[
+            Record::new(b"needle".to_vec(), 8, Some(b"straw".to_vec())),
+            Record::new(b"pin".to_vec(), 9, None),
+        ]);
+        let (records, size) = decode_entry(&batch, file, 0).unwrap().unwrap();
+        assert_eq!(size, batch.len());
+        assert_eq!(records.len(), 2);
+        assert!(records[1].tombstone && records[1].seq == 9);
+        assert!(decode_entry(&batch[..size - 1], file, 0).unwrap().is_none());
+
+        let mut block = seal(b"block");
+        assert_eq!(unseal(&block, file, 0).unwrap(), b"block");
+        block[0] ^= 1;
+        assert!(unseal(&block, file, 0).is_err());
     }
 }
@@ -0,0 +1,290 @@
+use crate::Result;
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+
+use super::{
+    super::data_format::Record, super::manifest::Edit, super::options::Options,
+    super::options::SyncPolicy, MemoryFileSystem, RandomAccess, WritableFile, FS,
+};
+
+// what goes wrong, shared by the clones
+#[derive(Default)]
+struct Faults {
+    // the write operations which still succeed, None for all of them
+    writes_left: Option<usize>,
+    // a log append or an edit of the manifest which fails leaves the first half of its bytes behind
+    torn: bool,
+    // the reads of the segments come back one byte short, the ones of the logs and the manifest too
+    short_reads: bool,
+}
+
+impl Faults {
+    fn write(&mut self) -> io::Result<()> {
+        match &mut self.writes_left {
+            Some(0) => Err(io::Error::other("injected write failure")),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// a `MemoryFileSystem` that fails on demand, to check what a storage makes of failures and crashes.
+/// Next to the files, it keeps what a power loss would leave of them: the bytes that were synced,
+/// in the same places `FileSystem` syncs them.
+/// A write operation is a log append, an edit of the manifest, the rotation or the clearing of the logs,
+/// or the finish of a segment,
+/// a failing one writes nothing, unless the writes are torn
+#[derive(Clone)]
+pub struct FaultyFileSystem {
+    inner: MemoryFileSystem,
+    durable: MemoryFileSystem,
+    sync: SyncPolicy,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyFileSystem {
+    pub fn new(options: &Options) -> Self {
+        FaultyFileSystem {
+            inner: MemoryFileSystem::new(options),
+            durable: MemoryFileSystem::new(options),
+            sync: options.sync,
+            faults: Default::default(),
+        }
+    }
+
+    /// the next `writes` write operations succeed, every one after them fails
+    pub fn fail_writes(&self, writes: usize) {
+        self.faults.lock().unwrap().writes_left = Some(writes);
+    }
+
+    /// as `fail_writes`, but the appends which fail are only partly written, as a crash in the middle leaves them
+    pub fn tear_writes(&self, writes: usize) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.writes_left = Some(writes);
+        faults.torn = true;
+    }
+
+    pub fn short_reads(&self) {
+        self.faults.lock().unwrap().short_reads = true;
+        self.inner.short_reads.store(true, Ordering::SeqCst);
+    }
+
+    /// stop failing
+    pub fn heal(&self) {
+        *self.faults.lock().unwrap() = Faults::default();
+        self.inner.short_reads.store(false, Ordering::SeqCst);
+    }
+
+    /// flip the bits of the byte at `offset` of the file, the segments opened before don't see it
+    pub fn corrupt(&self, path: &Path, offset: usize) {
+        for fs in [&self.inner, &self.durable] {
+            if let Some(bytes) = fs.read(path) {
+                let mut bytes = bytes.to_vec();
+                bytes[offset] ^= 0xff;
+                fs.write(path.to_path_buf(), bytes);
+            }
+        }
+    }
+
+    /// the files as the machine finds them once the power is back: only what was synced,
+    /// the file system in use goes on as if nothing happened
+    pub fn power_loss(&self) -> Self {
+        FaultyFileSystem {
+            inner: self.durable.copy(),
+            durable: self.durable.copy(),
+            sync: self.sync,
+            faults: Default::default(),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.inner.dir().join(name)
+    }
+
+    fn write(&self) -> Result<()> {
+        Ok(self.faults.lock().unwrap().write()?)
+    }
+
+    // the write operation appending `bytes` to the file, a torn one leaves the first half of them
+    fn append(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        let written = faults.write();
+        if written.is_err() && faults.torn {
+            let mut file = self.inner.read(path).map(|file| file.to_vec()).unwrap_or_default();
+            file.extend_from_slice(&bytes[..bytes.len() / 2]);
+            self.inner.write(path.to_path_buf(), file);
+        }
+        Ok(written?)
+    }
+
+    // the file is synced, a power loss leaves it as it is now
+    fn persist(&self, path: &Path) {
+        match self.inner.read(path) {
+            Some(bytes) => self.durable.write(path.to_path_buf(), bytes.to_vec()),
+            None => {
+                let _ = self.durable.take(path);
+            }
+        }
+    }
+}
+
+struct FaultyFile {
+    file: Arc<dyn RandomAccess>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl RandomAccess for FaultyFile {
+    fn read_at(&self, offset: u64, size: u64) -> Result<Cow<'_, [u8]>> {
+        let bytes = self.file.read_at(offset, size)?;
+        if !self.faults.lock().unwrap().short_reads || bytes.is_empty() {
+            return Ok(bytes);
+        }
+        Ok(Cow::Owned(bytes[..bytes.len() - 1].to_vec()))
+    }
+
+    fn size(&self) -> u64 {
+        self.file.size()
+    }
+}
+
+// a segment being written, the failure surfaces when it is finished, as a failing sync would
+struct FaultyWriter {
+    path: PathBuf,
+    file: Box<dyn WritableFile>,
+    fs: FaultyFileSystem,
+}
+
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WritableFile for FaultyWriter {
+    fn finish(self: Box<Self>) -> Result<Arc<dyn RandomAccess>> {
+        self.fs.write()?;
+        let file = self.file.finish()?;
+        self.fs.persist(&self.path);
+        Ok(Arc::new(FaultyFile {
+            file,
+            faults: self.fs.faults.clone(),
+        }))
+    }
+}
+
+impl FS for FaultyFileSystem {
+    fn dir(&self) -> &Path {
+        self.inner.dir()
+    }
+
+    fn data_files(&self) -> Result<Vec<(usize, PathBuf)>> {
+        self.inner.data_files()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.write()?;
+        self.inner.clear()?;
+        self.persist(&self.path("wal"));
+        self.persist(&self.path("wal.frozen"));
+        Ok(())
+    }
+
+    fn data_file(&self, level: usize, number: usize) -> PathBuf {
+        self.inner.data_file(level, number)
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        Ok(Box::new(FaultyWriter {
+            path: path.to_path_buf(),
+            file: self.inner.create(path)?,
+            fs: self.clone(),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccess>> {
+        Ok(Arc::new(FaultyFile {
+            file: self.inner.open(path)?,
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.inner.remove(path)?;
+        self.persist(path);
+        Ok(())
+    }
+
+    fn stats(&self) -> Vec<(String, u64)> {
+        self.inner.stats()
+    }
+
+    fn wal(&mut self, records: &[Record]) -> Result<()> {
+        self.append(&self.path("wal"), &self.inner.wal_entry(records))?;
+        self.inner.wal(records)?;
+        if self.sync == SyncPolicy::Always {
+            self.persist(&self.path("wal"));
+        }
+        Ok(())
+    }
+
+    fn replay_wal(&mut self) -> Result<Vec<Record>> {
+        let records = self.inner.replay_wal()?;
+        self.persist(&self.path("wal"));
+        self.persist(&self.path("wal.frozen"));
+        Ok(records)
+    }
+
+    // the log is synced before it is set aside, whatever the sync policy
+    fn rotate_wal(&mut self) -> Result<()> {
+        self.write()?;
+        self.inner.rotate_wal()?;
+        self.persist(&self.path("wal"));
+        self.persist(&self.path("wal.frozen"));
+        Ok(())
+    }
+
+    fn retire_wal(&mut self) -> Result<()> {
+        self.inner.retire_wal()?;
+        self.persist(&self.path("wal.frozen"));
+        Ok(())
+    }
+
+    fn read_manifest(&mut self) -> Result<Option<Vec<Edit>>> {
+        let edits = self.inner.read_manifest()?;
+        self.persist(&self.path("MANIFEST"));
+        Ok(edits)
+    }
+
+    fn reset_manifest(&mut self, edit: &Edit) -> Result<()> {
+        self.write()?;
+        self.inner.reset_manifest(edit)?;
+        self.persist(&self.path("MANIFEST"));
+        Ok(())
+    }
+
+    fn log_edit(&mut self, edit: &Edit) -> Result<()> {
+        self.append(&self.path("MANIFEST"), &edit.encode())?;
+        self.inner.log_edit(edit)?;
+        self.persist(&self.path("MANIFEST"));
+        Ok(())
+    }
+
+    fn move_dir(&mut self, dir: &Path) -> Result<()> {
+        self.inner.move_dir(dir)
+    }
+
+    fn attach_dir(&mut self, dir: &Path) -> Result<()> {
+        self.inner.attach_dir(dir)
+    }
+}
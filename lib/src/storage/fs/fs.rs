@@ -1,224 +1,670 @@
-use crate::{response::Response, storage::index::Index, utils::eq_u8, Error, ErrorKind, Result};
-use log::info;
+use crate::{Error, ErrorKind, Result};
+use crc32fast::hash as crc32;
+use log::{info, warn};
+use memmap2::Mmap;
 use std::{
-    ffi::OsStr,
+    borrow::Cow,
     fs::{self, remove_file, File, OpenOptions},
-    io::{Read, Write},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    mem::size_of,
     path::{Path, PathBuf},
+    slice,
+    sync::{Arc, Mutex},
 };
 
 pub static MAGIC: &[u8] = "dpdb-feff-1234-1".as_bytes();
 pub static META_SIZE: u64 = 16;
+// a log starts with it, the logs written before the entries were framed start with a record
+pub static LOG_MAGIC: &[u8] = "dpdb-wal-frame-1".as_bytes();
+// the frame of a log entry: len(entry)|crc(len)
+pub(super) const FRAME_SIZE: usize = size_of::<u64>() + data_format::CRC_SIZE;
 
-use super::{super::data_format::Record, FS};
+use super::{
+    super::cache::Lru, super::data_format, super::data_format::Record, super::manifest::Edit,
+    super::options::Options, super::options::ReadMode, super::options::SyncPolicy, RandomAccess,
+    WritableFile, FS,
+};
 
 pub struct FileSystem {
     // we won't expose the file to users
     // the directory represents the table
     pub dir: Box<PathBuf>,
-    // by default the name is `data`
-    // the more recent segment file has the smaller number suffix
-    // for example, data.1 is younger than data.2
+    // by default the name is `data`, a segment file is named after its level and its number,
+    // the more recent segment file has the bigger number suffix, whatever the level,
+    // for example, data.0.2 is younger than data.1.1
     pub file: String,
+    pub sync: SyncPolicy,
     pub wal_handle: File,
+    // where the last complete record of the log ends, None once a failed append couldn't be undone,
+    // the log then takes no more appends until it is replayed
+    wal_len: Option<u64>,
+    // the live segments are what the edits appended to it leave, see `manifest`
+    pub manifest_handle: File,
+    // where the last complete edit ends, the same as `wal_len` for the log
+    manifest_len: Option<u64>,
+    opener: Opener,
 }
 
-impl FS for FileSystem {
-    fn new(dir: &str) -> Result<Self> {
-        FileSystem::new(dir, "data")
+// the open segment files, shared by the segments of the directory,
+// the handles are locked while they are read, a read is a seek followed by read_exact
+type Handles = Mutex<Lru<PathBuf, Arc<Mutex<File>>>>;
+
+// opens the finished segments the way `ReadMode` says
+#[derive(Clone)]
+struct Opener {
+    read_mode: ReadMode,
+    handles: Arc<Handles>,
+}
+
+impl Opener {
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccess>> {
+        match self.read_mode {
+            ReadMode::Mmap => {
+                let file = File::open(path)?;
+                // segments are never written again once they are finished, nor truncated,
+                // they are only removed, which leaves the map intact
+                let map = unsafe { Mmap::map(&file)? };
+                Ok(Arc::new(MappedFile {
+                    path: path.to_path_buf(),
+                    map,
+                }))
+            }
+            ReadMode::Buffered => Ok(Arc::new(BufferedFile {
+                path: path.to_path_buf(),
+                size: fs::metadata(path)?.len(),
+                handles: self.handles.clone(),
+            })),
+        }
     }
+}
 
+// a segment read with seek and read, through the open files
+struct BufferedFile {
+    path: PathBuf,
+    size: u64,
+    handles: Arc<Handles>,
+}
+
+impl BufferedFile {
+    // the open file of the segment, opened on a miss
+    fn handle(&self) -> Result<Arc<Mutex<File>>> {
+        let mut handles = self.handles.lock().unwrap();
+        if let Some(file) = handles.get(&self.path) {
+            return Ok(file);
+        }
+        let file = Arc::new(Mutex::new(File::open(&self.path)?));
+        handles.insert(self.path.clone(), file.clone(), 1);
+        Ok(file)
+    }
+}
+
+impl RandomAccess for BufferedFile {
+    fn read_at(&self, offset: u64, size: u64) -> Result<Cow<'_, [u8]>> {
+        let file = self.handle()?;
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; size as usize];
+        file.read_exact(&mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+// a segment mapped into memory, a block is sliced out of the map without a syscall
+struct MappedFile {
+    path: PathBuf,
+    map: Mmap,
+}
+
+impl RandomAccess for MappedFile {
+    // a read past the end of the map is corruption
+    fn read_at(&self, offset: u64, size: u64) -> Result<Cow<'_, [u8]>> {
+        let start = offset as usize;
+        self.map
+            .get(start..start.saturating_add(size as usize))
+            .map(Cow::Borrowed)
+            .ok_or_else(|| Error::corruption(&self.path, offset))
+    }
+
+    fn size(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn is_mapped(&self) -> bool {
+        true
+    }
+}
+
+// a segment written under a temporary name, and renamed into place once it is complete
+struct TempFile {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: BufWriter<File>,
+    opener: Opener,
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WritableFile for TempFile {
+    fn finish(self: Box<Self>) -> Result<Arc<dyn RandomAccess>> {
+        self.file
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            sync_dir(dir)?;
+        }
+        self.opener.open(&self.path)
+    }
+}
+
+impl FS for FileSystem {
     fn dir(&self) -> &Path {
         &self.dir
     }
 
-    fn allocate_data_file(&self) -> Result<PathBuf> {
-        let files = FileSystem::scan_data_files(&self.dir)?;
-        // the first one must be `data` which is later renamed to `data.1`
-        let len = files.len();
-        let mut suffix = 0;
-        if len != 0 {
-            suffix = len;
+    fn data_files(&self) -> Result<Vec<(usize, PathBuf)>> {
+        FileSystem::scan_data_files(&self.dir, &self.file)
+    }
+
+    fn data_file(&self, level: usize, number: usize) -> PathBuf {
+        data_file_path(&self.dir, &self.file, level, number)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.wal_handle.set_len(0)?;
+        self.wal_handle.sync_all()?;
+        self.wal_len = Some(0);
+        let frozen = self.dir.join("wal.frozen");
+        if frozen.exists() {
+            fs::remove_file(frozen)?;
         }
-        Ok(self.dir.join("data").with_extension(suffix.to_string()))
+        Ok(())
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let tmp = temp_path(path);
+        Ok(Box::new(TempFile {
+            path: path.to_path_buf(),
+            file: BufWriter::new(File::create(&tmp)?),
+            tmp,
+            opener: self.opener.clone(),
+        }))
     }
 
-    fn meta_size() -> u64 {
-        META_SIZE
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccess>> {
+        self.opener.open(path)
     }
 
-    fn clear(&self) -> Result<()> {
+    // the open file is closed first, the name may be handed out again
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.opener
+            .handles
+            .lock()
+            .unwrap()
+            .remove(&path.to_path_buf());
+        fs::remove_file(path)?;
         Ok(())
     }
-}
 
-impl FileSystem {
-    pub fn new(dir: &str, file: &str) -> Result<Self> {
-        let dir = Path::new(dir);
-        let dbf = dir.join(file);
-        let wal = dir.join("wal");
-        info!("Open database file: {:?}", &dbf.to_str());
-        Ok(FileSystem {
-            dir: Box::new(dir.to_path_buf()),
-            file: file.to_owned(),
-            // write append log
-            wal_handle: OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(&wal)?,
-        })
+    fn stats(&self) -> Vec<(String, u64)> {
+        let handles = self.opener.handles.lock().unwrap();
+        vec![
+            ("file_cache_hits".to_string(), handles.hits),
+            ("file_cache_misses".to_string(), handles.misses),
+            ("file_cache_open".to_string(), handles.len() as u64),
+        ]
     }
 
-    pub fn wal(&mut self, line: &str) -> Result<()> {
-        self.wal_handle.write_fmt(format_args!("{}\n", line))?;
+    // an append which fails may leave part of its record behind, the records appended after it
+    // would be lost to the replay, which stops there, so the log is cut back to where it ended
+    fn wal(&mut self, records: &[Record]) -> Result<()> {
+        let len = self.wal_len.ok_or(Error {
+            kind: ErrorKind::IO,
+        })?;
+        let entry = log_entry(records, len == 0);
+        let appended = self
+            .wal_handle
+            .write_all(&entry)
+            .and_then(|_| match self.sync {
+                SyncPolicy::Always => self.wal_handle.sync_all(),
+                SyncPolicy::Never => Ok(()),
+            });
+        if let Err(e) = appended {
+            self.wal_len = self.wal_handle.set_len(len).ok().map(|_| len);
+            return Err(e.into());
+        }
+        self.wal_len = Some(len + entry.len() as u64);
+        Ok(())
+    }
+
+    // a crash may leave a half-written record at the tail of the log,
+    // everything before it was acknowledged, everything after it is garbage,
+    // so cut the log back to the last complete record before appending to it again
+    fn replay_wal(&mut self) -> Result<Vec<Record>> {
+        let path = self.dir.join("wal");
+        let frozen = self.dir.join("wal.frozen");
+        let log = read_all(&path)?;
+        let (mut records, len) = parse_log(&log, &path)?;
+        self.wal_handle.set_len(len as u64)?;
+        self.wal_len = Some(len as u64);
+        // the entries appended to a log written before they were framed would not be read back
+        let framed = len == 0 || log.starts_with(LOG_MAGIC);
+        if framed && !frozen.exists() {
+            return Ok(records);
+        }
+        // the flush of the frozen log never finished, its records are older than the live ones.
+        // Fold both into a new live log, so the next rotation has the place to itself,
+        // the torn tail of the frozen log is left out along the way.
+        // A crash before the frozen log is removed replays its records twice, which is harmless
+        let mut older = if frozen.exists() {
+            read_log(&frozen)?.0
+        } else {
+            vec![]
+        };
+        older.append(&mut records);
+        let tmp = self.dir.join("wal.tmp");
+        let mut log = File::create(&tmp)?;
+        let entries = log_entries(&older);
+        log.write_all(&entries)?;
+        log.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+        self.wal_handle = open_log(&path)?;
+        self.wal_len = Some(entries.len() as u64);
+        if frozen.exists() {
+            fs::remove_file(frozen)?;
+        }
+        Ok(older)
+    }
+
+    fn rotate_wal(&mut self) -> Result<()> {
         self.wal_handle.sync_all()?;
+        fs::rename(self.dir.join("wal"), self.dir.join("wal.frozen"))?;
+        sync_dir(&self.dir)?;
+        self.wal_handle = open_log(&self.dir.join("wal"))?;
+        self.wal_len = Some(0);
+        Ok(())
+    }
+
+    fn retire_wal(&mut self) -> Result<()> {
+        fs::remove_file(self.dir.join("wal.frozen"))?;
+        Ok(())
+    }
+
+    // an empty manifest is one that was never written, the directory predates it
+    fn read_manifest(&mut self) -> Result<Option<Vec<Edit>>> {
+        let path = self.dir.join("MANIFEST");
+        let manifest = read_all(&path)?;
+        if manifest.is_empty() {
+            return Ok(None);
+        }
+        let (edits, len) = parse_manifest(&manifest, &path)?;
+        if len < manifest.len() {
+            self.manifest_handle.set_len(len as u64)?;
+        }
+        self.manifest_len = Some(len as u64);
+        Ok(Some(edits))
+    }
+
+    fn reset_manifest(&mut self, edit: &Edit) -> Result<()> {
+        let path = self.dir.join("MANIFEST");
+        let tmp = self.dir.join("MANIFEST.tmp");
+        let mut manifest = File::create(&tmp)?;
+        let entry = edit.encode();
+        manifest.write_all(&entry)?;
+        manifest.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+        self.manifest_handle = open_log(&path)?;
+        self.manifest_len = Some(entry.len() as u64);
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn open_file_safely(file: &Path) -> Result<File> {
-        // Open it anyway, if it is empty, and write magic number into it
-        // else read 16 bytes from it, if that fails, abort,
-        // else check if the 16 bytes match the magic number, if so, return the file handle
-        // else, it's not safe to touch the file, abort
-        let mut db = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .append(true)
-            .open(file)?;
-        let file_metadata = db.metadata()?;
-        if file_metadata.len() == 0 {
-            db.write_all(MAGIC)?;
-            return Ok(db);
-        }
-        // magic number: dpdb-feff-1234-1
-        // is it really necessary?
-        let mut db_meta = [0u8; 16];
-        // may fail to read 16 bytes
-        db.read_exact(&mut db_meta)?;
-        if eq_u8(MAGIC, &db_meta) {
-            return Ok(db);
-        }
-        Err(Error {
+    // whatever the sync policy, an edit that is lost would lose the segments it adds,
+    // one which fails is cut off, as an append to the log is
+    fn log_edit(&mut self, edit: &Edit) -> Result<()> {
+        let len = self.manifest_len.ok_or(Error {
             kind: ErrorKind::IO,
-        })
+        })?;
+        let entry = edit.encode();
+        let logged = self
+            .manifest_handle
+            .write_all(&entry)
+            .and_then(|_| self.manifest_handle.sync_all());
+        if let Err(e) = logged {
+            self.manifest_len = self.manifest_handle.set_len(len).ok().map(|_| len);
+            return Err(e.into());
+        }
+        self.manifest_len = Some(len + entry.len() as u64);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn move_dir(&mut self, dir: &str) -> Result<()> {
-        let new_dir = Path::new(dir);
-        // make sure it's a directory
-        if !new_dir.is_dir() {
+    // the files are copied over and synced before the old ones are removed,
+    // so a crash in the middle leaves them complete where they were
+    fn move_dir(&mut self, dir: &Path) -> Result<()> {
+        if !dir.is_dir() || dir.read_dir()?.next().is_some() {
             return Err(Error {
                 kind: ErrorKind::File,
             });
         }
-        // the dir should be empty
-        let entries = new_dir.read_dir()?;
-        if entries.count() > 0 {
+        let files = self.own_files()?;
+        FileSystem::copy_files(&files, dir)?;
+        self.attach_dir(dir)?;
+        for file in files {
+            remove_file(file)?;
+        }
+        Ok(())
+    }
+
+    fn attach_dir(&mut self, dir: &Path) -> Result<()> {
+        if !dir.is_dir() {
             return Err(Error {
                 kind: ErrorKind::File,
             });
         }
-        // copy all files to the new dir
-        let curr_dir = &self.dir;
-        for entry in curr_dir.read_dir()? {
-            let entry = entry?;
-            let curr_file = curr_dir.join(entry.path());
-            fs::copy(&curr_file, new_dir.join(entry.path()))?;
-            remove_file(curr_file)?;
-        }
+        remove_temporaries(dir)?;
+        let wal = open_log(&dir.join("wal"))?;
+        let manifest = open_log(&dir.join("MANIFEST"))?;
+        self.wal_len = Some(wal.metadata()?.len());
+        self.manifest_len = Some(manifest.metadata()?.len());
+        self.wal_handle = wal;
+        self.manifest_handle = manifest;
+        *self.dir = dir.to_path_buf();
+        // the open files are those of the other directory
+        self.opener.handles.lock().unwrap().clear();
         Ok(())
     }
+}
 
-    #[allow(dead_code)]
-    pub fn attach_dir(&mut self, dir: &str) -> Result<()> {
-        *self = FileSystem::new(dir, "data")?;
-        Ok(())
+fn read_log(path: &Path) -> Result<(Vec<Record>, u64)> {
+    let (records, len) = parse_log(&read_all(path)?, path)?;
+    Ok((records, len as u64))
+}
+
+// the whole file, a read which comes back short fails rather than pass for a torn tail, which would be cut
+fn read_all(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut bytes = vec![0; file.metadata()?.len() as usize];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// how the records are appended to the log, several of them as a batch.
+/// An entry is framed as len(entry)|crc(len)|entry, the length has a checksum of its own,
+/// so a damaged one doesn't pass for an entry cut short by the end of the log.
+/// The first entry of a log follows its magic
+pub fn log_entry(records: &[Record], first: bool) -> Vec<u8> {
+    let entry = match records {
+        [record] => data_format::encode(record),
+        _ => data_format::encode_batch(records),
+    };
+    let len = (entry.len() as u64).to_be_bytes();
+    let magic = if first { LOG_MAGIC } else { &[] };
+    [magic, &len, &crc32(&len).to_be_bytes(), &entry].concat()
+}
+
+/// a whole log holding the records, one entry each
+pub fn log_entries(records: &[Record]) -> Vec<u8> {
+    records
+        .iter()
+        .enumerate()
+        .flat_map(|(i, record)| log_entry(slice::from_ref(record), i == 0))
+        .collect()
+}
+
+/// the complete records of a log and the length they take, a batch is unpacked into its records.
+/// An entry which runs past the end of the log, or the last one failing its checksum, was torn by a crash,
+/// it is left out, the same as a tail of zeros, which is where the size of the file made it to the disk
+/// before the bytes did. A damaged frame, or a damaged entry followed by others, is corruption,
+/// the entries after it were acknowledged
+pub fn parse_log(log: &[u8], path: &Path) -> Result<(Vec<Record>, usize)> {
+    let (records, len) = if log.starts_with(LOG_MAGIC) {
+        parse_entries(log, path)?
+    } else if LOG_MAGIC.starts_with(log) || log.iter().all(|&byte| byte == 0) {
+        // the crash came before the magic was written
+        (vec![], 0)
+    } else if log
+        .get(data_format::CRC_SIZE)
+        .is_none_or(|&kind| kind <= data_format::EXPIRING)
+    {
+        // a log written before the entries were framed, a damaged magic doesn't pass for one,
+        // its fifth byte would have to turn into the kind of a record
+        parse_records(log, path)?
+    } else {
+        return Err(Error::corruption(path, 0));
+    };
+    if len < log.len() {
+        warn!(
+            "dropping {} bytes of incomplete records from {}",
+            log.len() - len,
+            path.display()
+        );
     }
+    Ok((records, len))
+}
 
-    //pub fn read_record(&mut self) -> Result<Record> {
-    //    let h = self.read_handle.try_clone()?;
-    //    let (h, r) = FileSystem::read_record_with(h)?;
-    //    self.read_handle = h;
-    //    Ok(r)
-    //}
-    pub fn read_record_with(read_handle: &mut File) -> Result<Record> {
-        let mut pair_meta = [0u8; 16];
-        if read_handle.read_exact(&mut pair_meta).is_err() {
-            return Err(Error {
-                // corrupted file, or more likely reaching the end of database file
-                // but still can't find the ky
-                kind: ErrorKind::Key,
-            });
+// the framed entries after the magic of the log
+fn parse_entries(log: &[u8], path: &Path) -> Result<(Vec<Record>, usize)> {
+    let mut records = vec![];
+    let mut len = LOG_MAGIC.len();
+    while len < log.len() {
+        let rest = &log[len..];
+        let frame = match rest.get(..FRAME_SIZE) {
+            Some(frame) if rest.iter().any(|&byte| byte != 0) => frame,
+            _ => break,
+        };
+        let (size, crc) = frame.split_at(size_of::<u64>());
+        if crc32(size).to_be_bytes() != crc {
+            return Err(Error::corruption(path, len as u64));
+        }
+        // the length holds, so an entry which runs past the end of the log was cut short by it
+        let end = match (u64::from_be_bytes(size.try_into()?) as usize).checked_add(FRAME_SIZE) {
+            Some(end) if end <= rest.len() => end,
+            _ => break,
+        };
+        let at = (len + FRAME_SIZE) as u64;
+        match data_format::decode_entry(&rest[FRAME_SIZE..end], path, at) {
+            Ok(Some((mut entry, size))) if size == end - FRAME_SIZE => records.append(&mut entry),
+            _ if end == rest.len() => break,
+            Ok(_) => return Err(Error::corruption(path, at)),
+            Err(e) => return Err(e),
+        }
+        len += end;
+    }
+    Ok((records, len))
+}
+
+// the records of a log written before the entries were framed, a damaged length there
+// can't be told from a record cut short by the end of the log
+fn parse_records(log: &[u8], path: &Path) -> Result<(Vec<Record>, usize)> {
+    let mut records = vec![];
+    let mut len = 0;
+    loop {
+        let rest = &log[len..];
+        match data_format::decode_entry(rest, path, len as u64) {
+            Ok(Some((mut entry, size))) => {
+                len += size;
+                records.append(&mut entry);
+            }
+            Ok(None) => break,
+            Err(_) if data_format::encoded_size(rest) == Some(rest.len()) => break,
+            Err(_) if rest.iter().all(|&byte| byte == 0) => break,
+            Err(e) => return Err(e),
         }
-        let key_len = usize::from_be_bytes(pair_meta[..8].try_into()?);
-        let value_len = usize::from_be_bytes(pair_meta[8..16].try_into()?);
-        let mut pair_loaded: Vec<u8> = vec![0u8; key_len + value_len];
-        read_handle.read_exact(&mut pair_loaded)?;
-        let key_loaded = &pair_loaded[..key_len];
-        let value_loaded = &pair_loaded[key_len..(key_len + value_len)];
-        Ok(Record {
-            klen: key_len,
-            vlen: value_len,
-            key: key_loaded.to_vec(),
-            value: value_loaded.to_vec(),
+    }
+    Ok((records, len))
+}
+
+/// the complete edits of a manifest and the length they take, an incomplete edit can only be the last one
+pub fn parse_manifest(manifest: &[u8], path: &Path) -> Result<(Vec<Edit>, usize)> {
+    let mut edits = vec![];
+    let mut len = 0;
+    while let Some((edit, size)) = Edit::decode(&manifest[len..], path, len as u64)? {
+        len += size;
+        edits.push(edit);
+    }
+    if len < manifest.len() {
+        warn!(
+            "dropping {} bytes of an incomplete edit from {}",
+            manifest.len() - len,
+            path.display()
+        );
+    }
+    Ok((edits, len))
+}
+
+/// where a file is written before it is renamed into `path`, complete
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// make the renames and removals in the directory durable
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// the temporaries left behind by a crash never got their final names, nothing refers to them
+fn remove_temporaries(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            warn!("removing unfinished file {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(true)
+        .open(path)?)
+}
+
+impl FileSystem {
+    pub fn new(dir: &str, options: &Options) -> Result<Self> {
+        let dir = Path::new(dir);
+        remove_temporaries(dir)?;
+        let dbf = dir.join(&options.file);
+        let wal = dir.join("wal");
+        info!("Open database file: {:?}", &dbf.to_str());
+        // write append log
+        let wal = open_log(&wal)?;
+        let manifest = open_log(&dir.join("MANIFEST"))?;
+        Ok(FileSystem {
+            dir: Box::new(dir.to_path_buf()),
+            file: options.file.clone(),
+            sync: options.sync,
+            wal_len: Some(wal.metadata()?.len()),
+            wal_handle: wal,
+            manifest_len: Some(manifest.metadata()?.len()),
+            manifest_handle: manifest,
+            opener: Opener {
+                read_mode: options.read_mode,
+                handles: Arc::new(Mutex::new(Lru::new(options.open_files))),
+            },
         })
     }
 
-    pub fn scan_data_files(dir: &Path) -> Result<Vec<PathBuf>> {
-        let mut files = fs::read_dir(dir)?
+    // the segments, the logs and the manifest, the other files of the directory are not ours
+    fn own_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<_> = self
+            .data_files()?
+            .into_iter()
+            .map(|(_, file)| file)
+            .collect();
+        for name in ["wal", "wal.frozen", "MANIFEST"] {
+            let file = self.dir.join(name);
+            if file.exists() {
+                files.push(file);
+            }
+        }
+        Ok(files)
+    }
+
+    // copy the files into `dir` and sync them, the copies made so far are removed if one fails
+    fn copy_files(files: &[PathBuf], dir: &Path) -> Result<()> {
+        let mut copies = vec![];
+        let copied = files.iter().try_for_each(|file| {
+            let copy = dir.join(file.file_name().unwrap_or_default());
+            copies.push(copy.clone());
+            fs::copy(file, &copy)?;
+            File::open(&copy)?.sync_all()?;
+            Ok(())
+        });
+        if let Err(e) = copied.and_then(|_| sync_dir(dir)) {
+            for copy in copies {
+                let _ = remove_file(copy);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // the segment files are named after `file`, e.g.: data.0.0, data.1.3, ...
+    pub fn scan_data_files(dir: &Path, file: &str) -> Result<Vec<(usize, PathBuf)>> {
+        let files = fs::read_dir(dir)?
             .map(|res| res.map(|e| e.path()))
-            .filter(|e| match e {
-                Ok(path) => {
-                    // absolute path
-                    let path = path.file_name().unwrap().to_str().unwrap();
-                    if path.starts_with("data") {
-                        return true;
-                    }
-                    false
-                }
-                Err(_) => false,
-            })
             .collect::<std::result::Result<Vec<_>, std::io::Error>>()?;
-        let get_ext_as_num = |path: &PathBuf| {
-            path.extension()
-                .unwrap_or_else(|| OsStr::new("0"))
-                .to_str()
-                .unwrap()
-                .parse::<i32>()
-                .unwrap()
-        };
-        files.sort_by(|a, b| {
-            let a = get_ext_as_num(a);
-            let b = get_ext_as_num(b);
-            // a is smaller than b
-            // ascending order
-            a.cmp(&b)
-        });
-        Ok(files)
+        Ok(data_files(files, file))
     }
 }
 
-pub struct DBFile {
-    file: File,
+/// the segment files among `paths` with their levels, in ascending order of their numbers
+pub fn data_files(paths: Vec<PathBuf>, file: &str) -> Vec<(usize, PathBuf)> {
+    let mut files = paths
+        .into_iter()
+        .filter(|path| data_file_number(path, file).is_some())
+        .collect::<Vec<_>>();
+    files.sort_by_key(|path| data_file_number(path, file));
+    files
+        .into_iter()
+        .map(|path| (data_file_level(&path, file).unwrap_or(0), path))
+        .collect()
 }
 
-impl DBFile {
-    pub fn new(file: &Path) -> Result<Self> {
-        let file = FileSystem::open_file_safely(file)?;
-        Ok(DBFile { file })
-    }
+/// the path of the segment file of the level with the number
+pub fn data_file_path(dir: &Path, file: &str, level: usize, number: usize) -> PathBuf {
+    dir.join(format!("{}.{}.{}", file, level, number))
 }
 
-impl Iterator for DBFile {
-    type Item = Record;
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(record) = FileSystem::read_record_with(&mut self.file) {
-            return Some(record);
-        }
-        None
+// the parts after the name: `level.number`,
+// the segments written before there were levels are named `data.number`, they belong to level 0
+fn data_file_parts(path: &Path, file: &str) -> Option<(usize, usize)> {
+    let name = path.file_name()?.to_str()?;
+    let parts = name.strip_prefix(file)?.strip_prefix('.')?;
+    match parts.split_once('.') {
+        Some((level, number)) => Some((level.parse().ok()?, number.parse().ok()?)),
+        None => Some((0, parts.parse().ok()?)),
     }
 }
+
+/// the number of a segment file named after `file`, None for the other files
+pub fn data_file_number(path: &Path, file: &str) -> Option<usize> {
+    data_file_parts(path, file).map(|(_, number)| number)
+}
+
+fn data_file_level(path: &Path, file: &str) -> Option<usize> {
+    data_file_parts(path, file).map(|(level, _)| level)
+}
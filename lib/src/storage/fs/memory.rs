@@ -0,0 +1,298 @@
+use crate::{Error, ErrorKind, Result};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    super::data_format::Record,
+    super::manifest::Edit,
+    super::options::Options,
+    fs::{
+        data_file_path, data_files, log_entries, log_entry, parse_log, parse_manifest, LOG_MAGIC,
+    },
+    RandomAccess, WritableFile, FS,
+};
+
+// the files by their paths, a finished segment is shared with its readers
+type Files = Arc<Mutex<BTreeMap<PathBuf, Arc<Vec<u8>>>>>;
+
+/// keeps the files of a storage in memory, nothing touches the disk and nothing outlives the process.
+/// The clones share the files, so a storage can be reopened on them as if it was restarted
+#[derive(Clone)]
+pub struct MemoryFileSystem {
+    dir: PathBuf,
+    // the base name of the segment files, see `FileSystem`
+    file: String,
+    files: Files,
+    // where the last complete entries of the log and the manifest end, the next append goes there,
+    // over whatever a failed one left behind. None until the file is first read or written
+    wal_len: Option<usize>,
+    manifest_len: Option<usize>,
+    // the reads of the log and the manifest come back short, see `FaultyFileSystem`
+    #[cfg(test)]
+    pub(super) short_reads: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl MemoryFileSystem {
+    pub fn new(options: &Options) -> Self {
+        MemoryFileSystem {
+            dir: PathBuf::from("memory"),
+            file: options.file.clone(),
+            files: Default::default(),
+            wal_len: None,
+            manifest_len: None,
+            #[cfg(test)]
+            short_reads: Default::default(),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub(super) fn read(&self, path: &Path) -> Option<Arc<Vec<u8>>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    pub(super) fn write(&self, path: PathBuf, bytes: Vec<u8>) {
+        self.files.lock().unwrap().insert(path, Arc::new(bytes));
+    }
+
+    // append at `len`, the end of the file if None, and return where the file ends now
+    fn append(&self, path: PathBuf, len: Option<usize>, bytes: &[u8]) -> usize {
+        let mut files = self.files.lock().unwrap();
+        let file = Arc::make_mut(files.entry(path).or_default());
+        file.truncate(len.unwrap_or(file.len()));
+        file.extend_from_slice(bytes);
+        file.len()
+    }
+
+    // the next entry of the log, the first one follows the magic of the log
+    pub(super) fn wal_entry(&self, records: &[Record]) -> Vec<u8> {
+        let len = self
+            .wal_len
+            .unwrap_or_else(|| self.read(&self.path("wal")).map_or(0, |log| log.len()));
+        log_entry(records, len == 0)
+    }
+
+    // the whole of a log or the manifest, a read which comes back short fails as it does on disk
+    fn read_all(&self, path: &Path) -> Result<Option<Arc<Vec<u8>>>> {
+        #[cfg(test)]
+        if self.short_reads.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(self.read(path))
+    }
+
+    fn truncate(&self, path: PathBuf, len: usize) {
+        let mut files = self.files.lock().unwrap();
+        Arc::make_mut(files.entry(path).or_default()).truncate(len);
+    }
+
+    pub(super) fn take(&self, path: &Path) -> Result<Arc<Vec<u8>>> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .ok_or_else(|| not_found(path))
+    }
+
+    // a file system of its own holding the same files, the writes to either don't show in the other
+    #[cfg(test)]
+    pub(super) fn copy(&self) -> Self {
+        MemoryFileSystem {
+            files: Arc::new(Mutex::new(self.files.lock().unwrap().clone())),
+            short_reads: Default::default(),
+            ..self.clone()
+        }
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    io::Error::new(io::ErrorKind::NotFound, path.display().to_string()).into()
+}
+
+// a finished segment
+struct MemoryFile {
+    path: PathBuf,
+    bytes: Arc<Vec<u8>>,
+}
+
+impl RandomAccess for MemoryFile {
+    // a read past the end is corruption, as for a map of a file
+    fn read_at(&self, offset: u64, size: u64) -> Result<Cow<'_, [u8]>> {
+        let start = offset as usize;
+        self.bytes
+            .get(start..start.saturating_add(size as usize))
+            .map(Cow::Borrowed)
+            .ok_or_else(|| Error::corruption(&self.path, offset))
+    }
+
+    fn size(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+}
+
+// a segment being written, the others see it once it is finished
+struct PendingFile {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    files: Files,
+}
+
+impl Write for PendingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for PendingFile {
+    fn finish(self: Box<Self>) -> Result<Arc<dyn RandomAccess>> {
+        let bytes = Arc::new(self.bytes);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(self.path.clone(), bytes.clone());
+        Ok(Arc::new(MemoryFile {
+            path: self.path,
+            bytes,
+        }))
+    }
+}
+
+impl FS for MemoryFileSystem {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn data_files(&self) -> Result<Vec<(usize, PathBuf)>> {
+        let paths = self.files.lock().unwrap().keys().cloned().collect();
+        Ok(data_files(paths, &self.file))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.write(self.path("wal"), vec![]);
+        self.wal_len = Some(0);
+        let _ = self.take(&self.path("wal.frozen"));
+        Ok(())
+    }
+
+    fn data_file(&self, level: usize, number: usize) -> PathBuf {
+        data_file_path(&self.dir, &self.file, level, number)
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        Ok(Box::new(PendingFile {
+            path: path.to_path_buf(),
+            bytes: vec![],
+            files: self.files.clone(),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccess>> {
+        let bytes = self.read(path).ok_or_else(|| not_found(path))?;
+        Ok(Arc::new(MemoryFile {
+            path: path.to_path_buf(),
+            bytes,
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.take(path)?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Vec<(String, u64)> {
+        vec![]
+    }
+
+    fn wal(&mut self, records: &[Record]) -> Result<()> {
+        let entry = self.wal_entry(records);
+        self.wal_len = Some(self.append(self.path("wal"), self.wal_len, &entry));
+        Ok(())
+    }
+
+    // the same as for the files on disk, see `FileSystem`
+    fn replay_wal(&mut self) -> Result<Vec<Record>> {
+        let path = self.path("wal");
+        let log = self.read_all(&path)?.unwrap_or_default();
+        let (mut records, len) = parse_log(&log, &path)?;
+        self.truncate(path.clone(), len);
+        self.wal_len = Some(len);
+        let framed = len == 0 || log.starts_with(LOG_MAGIC);
+        let frozen = self.path("wal.frozen");
+        let mut older = match self.read_all(&frozen)? {
+            Some(older) => parse_log(&older, &frozen)?.0,
+            None if framed => return Ok(records),
+            None => vec![],
+        };
+        older.append(&mut records);
+        let log = log_entries(&older);
+        self.wal_len = Some(log.len());
+        self.write(path, log);
+        let _ = self.take(&frozen);
+        Ok(older)
+    }
+
+    fn rotate_wal(&mut self) -> Result<()> {
+        let log = self.take(&self.path("wal")).unwrap_or_default();
+        self.files
+            .lock()
+            .unwrap()
+            .insert(self.path("wal.frozen"), log);
+        self.wal_len = Some(0);
+        Ok(())
+    }
+
+    fn retire_wal(&mut self) -> Result<()> {
+        self.take(&self.path("wal.frozen"))?;
+        Ok(())
+    }
+
+    fn read_manifest(&mut self) -> Result<Option<Vec<Edit>>> {
+        let path = self.path("MANIFEST");
+        let manifest = match self.read_all(&path)? {
+            Some(manifest) if !manifest.is_empty() => manifest,
+            _ => return Ok(None),
+        };
+        let (edits, len) = parse_manifest(&manifest, &path)?;
+        self.truncate(path, len);
+        self.manifest_len = Some(len);
+        Ok(Some(edits))
+    }
+
+    fn reset_manifest(&mut self, edit: &Edit) -> Result<()> {
+        let manifest = edit.encode();
+        self.manifest_len = Some(manifest.len());
+        self.write(self.path("MANIFEST"), manifest);
+        Ok(())
+    }
+
+    fn log_edit(&mut self, edit: &Edit) -> Result<()> {
+        let path = self.path("MANIFEST");
+        self.manifest_len = Some(self.append(path, self.manifest_len, &edit.encode()));
+        Ok(())
+    }
+
+    // there are no directories to move to, nor to attach
+    fn move_dir(&mut self, _: &Path) -> Result<()> {
+        Err(Error {
+            kind: ErrorKind::File,
+        })
+    }
+
+    fn attach_dir(&mut self, _: &Path) -> Result<()> {
+        Err(Error {
+            kind: ErrorKind::File,
+        })
+    }
+}
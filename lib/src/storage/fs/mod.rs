@@ -1,17 +1,74 @@
+#[cfg(test)]
+mod faulty;
 mod fs;
+mod memory;
+#[cfg(test)]
 mod test;
 
 
+use std::borrow::Cow;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use crate::Result;
-pub use fs::DBFile;
 pub use fs::FileSystem;
+pub use memory::MemoryFileSystem;
+pub use fs::{data_file_number, sync_dir, MAGIC, META_SIZE};
+#[cfg(test)]
+pub(crate) use fs::log_entry;
+use super::data_format::Record;
+use super::manifest::Edit;
 
-pub trait FS: Sized {
-    fn new(dir: &str) -> Result<Self>;
+/// a finished segment file, read at any offset by any thread
+pub trait RandomAccess: Send + Sync {
+    fn read_at(&self, offset: u64, size: u64) -> Result<Cow<'_, [u8]>>;
+    fn size(&self) -> u64;
+    // whether the reads are served from a map of the file, see `ReadMode`
+    fn is_mapped(&self) -> bool {
+        false
+    }
+}
+
+/// a segment file being written, it only takes its name once `finish` returns,
+/// so a crash never leaves a partial segment under the name of a complete one
+pub trait WritableFile: Write + Send {
+    // make the file durable under its name, and open it for reading
+    fn finish(self: Box<Self>) -> Result<Arc<dyn RandomAccess>>;
+}
+
+/// every file the storage touches goes through here: the segments, the log and the manifest
+pub trait FS: Send {
     fn dir(&self) -> &Path;
-    fn clear(&self) -> Result<()>;
-    fn allocate_data_file(&self) -> Result<PathBuf>;
-    fn meta_size() -> u64;
+    // the segment files with their levels, from the oldest to the youngest
+    fn data_files(&self) -> Result<Vec<(usize, PathBuf)>>;
+    // empty the logs, the writes they hold are gone along with the memtables, see `Storage::truncate`
+    fn clear(&mut self) -> Result<()>;
+    // the path of the segment file of the level with the number, see `Storage` for the numbering
+    fn data_file(&self, level: usize, number: usize) -> PathBuf;
+    // a new segment file
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+    // a finished segment file
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccess>>;
+    // a segment file which is no longer live
+    fn remove(&self, path: &Path) -> Result<()>;
+    // the counters of the open files, as name and value pairs
+    fn stats(&self) -> Vec<(String, u64)>;
+    // the records are logged as one unit, a crash keeps all of them or none
+    fn wal(&mut self, records: &[Record]) -> Result<()>;
+    fn replay_wal(&mut self) -> Result<Vec<Record>>;
+    // set the log aside along with the memtable it backs, and start an empty one
+    fn rotate_wal(&mut self) -> Result<()>;
+    // drop the log set aside by `rotate_wal`, its memtable is durable in a segment
+    fn retire_wal(&mut self) -> Result<()>;
+    // the edits of the manifest, None when the directory has none yet
+    fn read_manifest(&mut self) -> Result<Option<Vec<Edit>>>;
+    // replace the manifest with one holding only `edit`
+    fn reset_manifest(&mut self, edit: &Edit) -> Result<()>;
+    // append the edit to the manifest, it is synced before this returns
+    fn log_edit(&mut self, edit: &Edit) -> Result<()>;
+    // move the files of the storage to `dir`, an empty directory, and go on there
+    fn move_dir(&mut self, dir: &Path) -> Result<()>;
+    // go on with the files of `dir`, the ones of the current directory are left as they are
+    fn attach_dir(&mut self, dir: &Path) -> Result<()>;
 }
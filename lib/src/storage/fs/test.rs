@@ -1,30 +1,328 @@
-use crate::executor::Executor;
+use super::faulty::FaultyFileSystem;
+use super::fs::{FRAME_SIZE, LOG_MAGIC};
+use super::{MemoryFileSystem, FS};
+use crate::storage::data_format::{self, Record};
+use crate::storage::options::{Builder, Options, SyncPolicy};
 use crate::storage::Storage;
-use crate::Result;
-use std::{fs::File, path::{PathBuf, Path}};
-use tempfile::{tempdir, tempfile, NamedTempFile, TempDir};
+use crate::{Error, ErrorKind, Result};
+use std::ops::Range;
+use std::path::Path;
 
-struct MockFileSystem {
-    dir: TempDir,
+fn storage(fs: &MemoryFileSystem) -> Storage {
+    let options = Builder::new().memtable_size(64).build().unwrap();
+    Storage::with_fs(Box::new(fs.clone()), options).unwrap()
 }
 
-impl MockFileSystem {
-    fn new() -> Result<Self> {
-        let dir = tempdir()?;
-        let mut file1 = NamedTempFile::new()?;
-        Ok(MockFileSystem { dir })
+fn value(storage: &Storage, key: &str) -> Option<Vec<u8>> {
+    storage
+        .lookup(key.as_bytes(), None)
+        .unwrap()
+        .filter(|record| !record.tombstone)
+        .map(|record| record.value)
+}
+
+#[test]
+fn memory() {
+    let fs = MemoryFileSystem::new(&Default::default());
+    {
+        let mut storage = storage(&fs);
+        for round in 0..4 {
+            for i in 0..16 {
+                storage
+                    .set(
+                        format!("key{:02}", i).as_bytes(),
+                        format!("value{}", round).as_bytes(),
+                    )
+                    .unwrap();
+            }
+        }
+        storage.del(b"key00").unwrap();
+        assert!(fs.data_files().unwrap().len() > 1);
+        storage.compact().unwrap();
+        assert_eq!(fs.data_files().unwrap().len(), 1);
+        assert_eq!(value(&storage, "key03"), Some(b"value3".to_vec()));
+        // written after the compaction, only the log holds it
+        storage.set(b"needle", b"hay").unwrap();
     }
+    // the clone sees the files left behind, as a restart would
+    let storage = storage(&fs);
+    assert_eq!(value(&storage, "key00"), None);
+    assert_eq!(value(&storage, "key15"), Some(b"value3".to_vec()));
+    assert_eq!(value(&storage, "needle"), Some(b"hay".to_vec()));
+    assert_eq!(storage.range(b"key", b"kez").count(), 15);
+    assert!(!Path::new("memory").exists());
+
+    // the files are not shared between file systems which are not clones
+    let other = MemoryFileSystem::new(&Default::default());
+    assert_eq!(value(&self::storage(&other), "needle"), None);
+}
+
+fn faulty(sync: SyncPolicy) -> Options {
+    Builder::new()
+        .memtable_size(64)
+        .sync(sync)
+        .compaction_trigger(2)
+        .build()
+        .unwrap()
+}
+
+fn open(fs: &FaultyFileSystem, sync: SyncPolicy) -> Result<Storage> {
+    Storage::with_fs(Box::new(fs.clone()), faulty(sync))
+}
 
-    fn dir(&self) -> &Path {
-        self.dir.path()
+fn key(i: usize) -> String {
+    format!("key{:03}", i)
+}
+
+fn expected(i: usize) -> Vec<u8> {
+    format!("value{}", i).into_bytes()
+}
+
+// the keys of `keys` the storage holds, every one of them with its own value
+fn survivors(storage: &Storage, keys: Range<usize>) -> Vec<usize> {
+    keys.filter(|&i| match value(storage, &key(i)) {
+        Some(value) => {
+            assert_eq!(value, expected(i), "{}", key(i));
+            true
+        }
+        None => false,
+    })
+    .collect()
+}
+
+#[test]
+fn power_loss() {
+    // whenever the power goes, the writes which were acknowledged are kept
+    let fs = FaultyFileSystem::new(&faulty(SyncPolicy::Always));
+    let mut storage = open(&fs, SyncPolicy::Always).unwrap();
+    for i in 0..200 {
+        storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+        storage.sweep().unwrap();
+        if i % 13 == 0 {
+            let crashed = open(&fs.power_loss(), SyncPolicy::Always).unwrap();
+            assert_eq!(survivors(&crashed, 0..200).len(), i + 1);
+        }
     }
+    assert!(fs.data_files().unwrap().len() > 1);
 
-    fn clear() -> Result<()> {
-        Ok(())
+    // without syncs the latest writes may be lost, but never one without those before it
+    let fs = FaultyFileSystem::new(&faulty(SyncPolicy::Never));
+    let mut storage = open(&fs, SyncPolicy::Never).unwrap();
+    for i in 0..200 {
+        storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+        if i % 13 == 0 {
+            let crashed = open(&fs.power_loss(), SyncPolicy::Never).unwrap();
+            let kept = survivors(&crashed, 0..200);
+            assert_eq!(kept, (0..kept.len()).collect::<Vec<_>>());
+        }
     }
+}
 
-    pub fn allocate_data_file(&self) -> Result<PathBuf> {
-        Ok(self.dir.path().join("data"))
+#[test]
+fn failed_writes() {
+    for (writes, torn) in (0..40).flat_map(|writes| [(writes, false), (writes, true)]) {
+        let fs = FaultyFileSystem::new(&faulty(SyncPolicy::Always));
+        let mut storage = open(&fs, SyncPolicy::Always).unwrap();
+        match torn {
+            true => fs.tear_writes(writes),
+            false => fs.fail_writes(writes),
+        }
+        let mut acknowledged = vec![];
+        for i in 0..100 {
+            let seq = storage.snapshot().seq;
+            match storage.set(key(i).as_bytes(), &expected(i)) {
+                Ok(_) => acknowledged.push(i),
+                // a write which fails takes no sequence number
+                Err(_) => assert_eq!(storage.snapshot().seq, seq),
+            }
+        }
+        assert!(acknowledged.len() <= writes);
+        // a flush which failed is done again once the writes succeed,
+        // the one in flight fails before the heal, rather than on the first write after it
+        let _ = storage.wait_flush();
+        fs.heal();
+        for i in 100..120 {
+            storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+            acknowledged.push(i);
+        }
+        let crashed = open(&fs.power_loss(), SyncPolicy::Always).unwrap();
+        drop(storage);
+        let restarted = open(&fs, SyncPolicy::Always).unwrap();
+        for storage in [crashed, restarted] {
+            let kept = survivors(&storage, 0..120);
+            assert!(acknowledged.iter().all(|i| kept.contains(i)));
+        }
     }
 }
 
+#[test]
+fn short_reads() {
+    let fs = FaultyFileSystem::new(&faulty(SyncPolicy::Always));
+    {
+        let mut storage = open(&fs, SyncPolicy::Always).unwrap();
+        for i in 0..100 {
+            storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+        }
+    }
+    let storage = open(&fs, SyncPolicy::Always).unwrap();
+    fs.short_reads();
+    // a read cut short is an error, never a value
+    let failed = (0..100)
+        .filter(|&i| match storage.lookup(key(i).as_bytes(), None) {
+            Ok(record) => {
+                assert_eq!(record.unwrap().value, expected(i));
+                false
+            }
+            Err(_) => true,
+        })
+        .count();
+    assert!(failed > 0);
+    assert!(open(&fs, SyncPolicy::Always).is_err());
+    fs.heal();
+    assert_eq!(survivors(&storage, 0..100).len(), 100);
+
+    // a log read short fails the replay, rather than cut the records it missed
+    let options = Builder::new().memtable_size(1 << 20).build().unwrap();
+    let fs = FaultyFileSystem::new(&options);
+    {
+        let mut storage = Storage::with_fs(Box::new(fs.clone()), options.clone()).unwrap();
+        for i in 0..10 {
+            storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+        }
+    }
+    fs.short_reads();
+    assert!(Storage::with_fs(Box::new(fs.clone()), options.clone()).is_err());
+    fs.heal();
+    let storage = Storage::with_fs(Box::new(fs.clone()), options).unwrap();
+    assert_eq!(survivors(&storage, 0..10).len(), 10);
+}
+
+#[test]
+fn corruption() {
+    let fs = FaultyFileSystem::new(&faulty(SyncPolicy::Always));
+    {
+        let mut storage = open(&fs, SyncPolicy::Always).unwrap();
+        for i in 0..100 {
+            storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+        }
+    }
+    // the replay flushes the log, then nothing is written while the storage is only read
+    drop(open(&fs, SyncPolicy::Always).unwrap());
+    let (_, path) = fs.data_files().unwrap().pop().unwrap();
+    let size = fs.open(&path).unwrap().size() as usize;
+    // a damaged segment either fails to open or fails the reads of the damaged block,
+    // whatever is read from it is what was written
+    let mut failures = 0;
+    for offset in (0..size).step_by(7) {
+        fs.corrupt(&path, offset);
+        match open(&fs, SyncPolicy::Always) {
+            Ok(storage) => {
+                for i in 0..100 {
+                    match storage.lookup(key(i).as_bytes(), None) {
+                        Ok(record) => assert_eq!(record.unwrap().value, expected(i)),
+                        Err(_) => failures += 1,
+                    }
+                }
+            }
+            Err(_) => failures += 1,
+        }
+        fs.corrupt(&path, offset);
+    }
+    assert!(failures > 0);
+
+    // a damaged record followed by others fails the replay, they were acknowledged,
+    // only the last record is dropped, a crash could have torn it
+    let options = Builder::new().memtable_size(1 << 20).build().unwrap();
+    for offset in (0..).step_by(7) {
+        let fs = FaultyFileSystem::new(&options);
+        {
+            let mut storage = Storage::with_fs(Box::new(fs.clone()), options.clone()).unwrap();
+            for i in 0..10 {
+                storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+            }
+        }
+        let wal = fs.path("wal");
+        let len = fs.open(&wal).unwrap().size() as usize;
+        if offset >= len {
+            break;
+        }
+        // the records are all the same size, they follow the magic of the log
+        let size = (len - LOG_MAGIC.len()) / 10;
+        let (record, at) = match offset.checked_sub(LOG_MAGIC.len()) {
+            Some(offset) => (offset / size, offset % size),
+            None => (0, 0),
+        };
+        fs.corrupt(&wal, offset);
+        match Storage::with_fs(Box::new(fs.clone()), options.clone()) {
+            Err(Error {
+                kind: ErrorKind::Corruption { offset: found, .. },
+            }) => {
+                let start = LOG_MAGIC.len() + record * size;
+                if offset < LOG_MAGIC.len() {
+                    assert_eq!(found, 0);
+                } else {
+                    // a damaged frame doesn't tell where its entry ends, even when it is the last one
+                    assert!(record < 9 || at < FRAME_SIZE, "{}", offset);
+                    assert!([start, start + FRAME_SIZE].contains(&(found as usize)));
+                }
+            }
+            Ok(storage) => {
+                assert!(record == 9, "{}", offset);
+                assert_eq!(survivors(&storage, 0..10), (0..record).collect::<Vec<_>>());
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+#[test]
+fn unframed_log() {
+    // a log written before the entries were framed, its torn tail is dropped as before
+    let fs = MemoryFileSystem::new(&Default::default());
+    let mut log: Vec<_> = (0..4)
+        .flat_map(|i| {
+            data_format::encode(&Record::new(
+                key(i).into_bytes(),
+                i as u64 + 1,
+                Some(expected(i)),
+            ))
+        })
+        .collect();
+    log.truncate(log.len() - 1);
+    fs.write(fs.dir().join("wal"), log);
+    {
+        let mut storage = storage(&fs);
+        assert_eq!(value(&storage, &key(2)), Some(expected(2)));
+        assert_eq!(value(&storage, &key(3)), None);
+        storage.set(b"needle", b"hay").unwrap();
+    }
+    // it was rewritten with frames before the append
+    let log = fs.read(&fs.dir().join("wal")).unwrap();
+    assert!(log.starts_with(LOG_MAGIC));
+    let storage = storage(&fs);
+    assert_eq!(value(&storage, &key(0)), Some(expected(0)));
+    assert_eq!(value(&storage, "needle"), Some(b"hay".to_vec()));
+}
+
+#[test]
+fn truncate() {
+    let mut outcomes = vec![];
+    for writes in 0..4 {
+        let fs = FaultyFileSystem::new(&faulty(SyncPolicy::Always));
+        let mut storage = open(&fs, SyncPolicy::Always).unwrap();
+        for i in 0..100 {
+            storage.set(key(i).as_bytes(), &expected(i)).unwrap();
+        }
+        fs.fail_writes(writes);
+        let truncated = storage.truncate().is_ok();
+        // the edit of the manifest is the point of no return, the clearing of the logs may fail after it,
+        // either way a crash keeps what the storage shows
+        let crashed = open(&fs.power_loss(), SyncPolicy::Always).unwrap();
+        let kept = survivors(&crashed, 0..100).len();
+        assert!(kept == 0 || kept == 100);
+        assert_eq!(survivors(&storage, 0..100).len(), kept);
+        assert!(!truncated || kept == 0);
+        outcomes.push(kept);
+    }
+    assert!(outcomes.contains(&0) && outcomes.contains(&100));
+}
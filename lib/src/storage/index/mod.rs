@@ -1,38 +1,85 @@
-use std::collections::BTreeMap;
+use crate::{Error, ErrorKind, Result};
+use std::mem::size_of;
 
-#[derive(Debug)]
-pub struct Node {
+// where a block sits in the segment file
+#[derive(Debug, Clone, Copy)]
+pub struct BlockHandle {
     pub(crate) offset: u64,
-    pub(crate) segment: String,
+    pub(crate) size: u64,
 }
 
+/// the sparse index of a segment: the first key of every block,
+/// a key can only live in the last block whose first key is not greater than it
 #[derive(Debug)]
 pub(crate) struct Index {
-    pub(crate) internal: BTreeMap<Vec<u8>, Node>,
+    pub(crate) internal: Vec<(Vec<u8>, BlockHandle)>,
 }
 
 impl Index {
     pub fn new() -> Self {
         Index {
-            internal: BTreeMap::new(),
+            internal: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, first_key: &[u8], handle: BlockHandle) {
+        self.internal.push((first_key.to_vec(), handle))
+    }
+
+    // the position of the block that may hold the key
+    fn position(&self, k: &[u8]) -> Option<usize> {
+        match self
+            .internal
+            .partition_point(|(first_key, _)| first_key.as_slice() <= k)
+        {
+            0 => None,
+            n => Some(n - 1),
         }
     }
 
-    pub fn insert(&mut self, k: &[u8], segment: &str, offset: u64) -> Option<Node> {
-        self.internal.insert(
-            k.to_vec(),
-            Node {
-                segment: segment.to_string(),
-                offset,
-            },
-        )
+    pub fn get(&self, k: &[u8]) -> Option<&BlockHandle> {
+        self.position(k).map(|i| &self.internal[i].1)
     }
 
-    pub fn get(&self, k: &[u8]) -> Option<&Node> {
-        self.internal.get(k)
+    /// the blocks that may hold keys not less than `k`
+    pub fn blocks_from(&self, k: &[u8]) -> Vec<BlockHandle> {
+        let start = self.position(k).unwrap_or(0);
+        self.internal[start..].iter().map(|(_, h)| *h).collect()
     }
 
-    pub fn clear(&mut self) {
-        self.internal.clear()
+    /// the layout of an entry is: len(key)|key|offset|size
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        for (key, handle) in &self.internal {
+            buf.extend_from_slice(&key.len().to_be_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&handle.offset.to_be_bytes());
+            buf.extend_from_slice(&handle.size.to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        let malformed = || Error {
+            kind: ErrorKind::File,
+        };
+        let mut index = Index::new();
+        while !buf.is_empty() {
+            let klen = usize::from_be_bytes(
+                buf.get(..size_of::<usize>())
+                    .ok_or_else(malformed)?
+                    .try_into()?,
+            );
+            buf = &buf[size_of::<usize>()..];
+            let entry = klen.checked_add(16).ok_or_else(malformed)?;
+            if buf.len() < entry {
+                return Err(malformed());
+            }
+            let offset = u64::from_be_bytes(buf[klen..klen + 8].try_into()?);
+            let size = u64::from_be_bytes(buf[klen + 8..klen + 16].try_into()?);
+            index.push(&buf[..klen], BlockHandle { offset, size });
+            buf = &buf[entry..];
+        }
+        Ok(index)
     }
 }
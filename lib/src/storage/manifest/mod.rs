@@ -0,0 +1,233 @@
+use super::data_format;
+use super::segment::Segment;
+use crate::{Error, Result};
+use std::mem::size_of;
+use std::path::Path;
+
+/// a live segment as the manifest knows it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentMeta {
+    pub level: usize,
+    // the name of the file in the data directory
+    pub name: String,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+}
+
+impl SegmentMeta {
+    pub fn of(level: usize, segment: &Segment) -> Self {
+        SegmentMeta {
+            level,
+            name: file_name(&segment.path),
+            smallest: segment.smallest.clone(),
+            largest: segment.largest.clone(),
+        }
+    }
+}
+
+/// the name a segment is known by in the manifest
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// a change to the set of live segments, logged to the manifest as one unit:
+/// a flush adds a segment, a compaction adds its outputs and removes its inputs at once
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Edit {
+    pub added: Vec<SegmentMeta>,
+    // the names of the segments which are gone
+    pub removed: Vec<String>,
+    // the writes up to this sequence number are gone, the log may still hold some of them,
+    // see `Storage::truncate`
+    pub cleared: Option<u64>,
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take_u64(buf: &mut &[u8]) -> Option<u64> {
+    let n = u64::from_be_bytes(buf.get(..size_of::<u64>())?.try_into().ok()?);
+    *buf = &buf[size_of::<u64>()..];
+    Some(n)
+}
+
+fn take_bytes(buf: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = take_u64(buf)? as usize;
+    let bytes = buf.get(..len)?.to_vec();
+    *buf = &buf[len..];
+    Some(bytes)
+}
+
+fn take_name(buf: &mut &[u8]) -> Option<String> {
+    String::from_utf8(take_bytes(buf)?).ok()
+}
+
+impl Edit {
+    /// the layout of an entry is: len|count(added)|(level|name|smallest|largest)...|count(removed)|name...|[cleared]|crc,
+    /// len covers what follows it, names and keys are prefixed with their lengths
+    pub fn encode(&self) -> Vec<u8> {
+        let mut edit = vec![];
+        edit.extend_from_slice(&(self.added.len() as u64).to_be_bytes());
+        for meta in &self.added {
+            edit.extend_from_slice(&(meta.level as u64).to_be_bytes());
+            put_bytes(&mut edit, meta.name.as_bytes());
+            put_bytes(&mut edit, &meta.smallest);
+            put_bytes(&mut edit, &meta.largest);
+        }
+        edit.extend_from_slice(&(self.removed.len() as u64).to_be_bytes());
+        for name in &self.removed {
+            put_bytes(&mut edit, name.as_bytes());
+        }
+        if let Some(seq) = self.cleared {
+            edit.extend_from_slice(&seq.to_be_bytes());
+        }
+        let mut buf = vec![];
+        put_bytes(&mut buf, &data_format::seal(&edit));
+        buf
+    }
+
+    /// the edit at the start of `buf`, which sits at `offset` of `file`, and the bytes it takes.
+    /// None for an entry cut short, or a damaged last entry, which is what a crash while appending leaves behind,
+    /// a damaged entry followed by others is corruption
+    pub fn decode(buf: &[u8], file: &Path, offset: u64) -> Result<Option<(Edit, usize)>> {
+        let mut rest = buf;
+        let entry = match take_bytes(&mut rest) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let edit = match data_format::unseal(&entry, file, offset) {
+            Ok(edit) => edit,
+            Err(_) if rest.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let edit = Edit::parse(edit).ok_or_else(|| Error::corruption(file, offset))?;
+        Ok(Some((edit, buf.len() - rest.len())))
+    }
+
+    fn parse(mut buf: &[u8]) -> Option<Edit> {
+        let buf = &mut buf;
+        let mut edit = Edit::default();
+        for _ in 0..take_u64(buf)? {
+            edit.added.push(SegmentMeta {
+                level: take_u64(buf)? as usize,
+                name: take_name(buf)?,
+                smallest: take_bytes(buf)?,
+                largest: take_bytes(buf)?,
+            });
+        }
+        for _ in 0..take_u64(buf)? {
+            edit.removed.push(take_name(buf)?);
+        }
+        // the entries written before there was truncation end here
+        if !buf.is_empty() {
+            edit.cleared = Some(take_u64(buf)?);
+        }
+        buf.is_empty().then_some(edit)
+    }
+}
+
+/// the live segments, as the edits leave them: by level, each level in the order its segments were added
+#[derive(Debug, Default)]
+pub struct Version {
+    pub levels: Vec<Vec<SegmentMeta>>,
+    // the latest sequence number the storage was truncated at, 0 if it never was
+    pub cleared: u64,
+}
+
+impl Version {
+    pub fn apply(&mut self, edit: &Edit) {
+        for level in self.levels.iter_mut() {
+            level.retain(|meta| !edit.removed.contains(&meta.name));
+        }
+        for meta in &edit.added {
+            if self.levels.len() <= meta.level {
+                self.levels.resize_with(meta.level + 1, Vec::new);
+            }
+            self.levels[meta.level].push(meta.clone());
+        }
+        if let Some(seq) = edit.cleared {
+            self.cleared = self.cleared.max(seq);
+        }
+    }
+
+    /// the edit which builds this version from nothing, a new manifest starts with it
+    pub fn snapshot(&self) -> Edit {
+        Edit {
+            added: self.levels.iter().flatten().cloned().collect(),
+            removed: vec![],
+            cleared: (self.cleared > 0).then_some(self.cleared),
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.levels.iter().flatten().any(|meta| meta.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn meta(level: usize, name: &str) -> SegmentMeta {
+        SegmentMeta {
+            level,
+            name: name.to_string(),
+            smallest: b"a".to_vec(),
+            largest: b"z".to_vec(),
+        }
+    }
+
+    #[test]
+    fn manifest() {
+        let file = Path::new("MANIFEST");
+        let flush = Edit {
+            added: vec![meta(0, "data.0.0")],
+            removed: vec![],
+            cleared: None,
+        };
+        let compaction = Edit {
+            added: vec![meta(1, "data.1.1"), meta(1, "data.1.2")],
+            removed: vec!["data.0.0".to_string()],
+            cleared: None,
+        };
+        let log = [flush.encode(), compaction.encode()].concat();
+        let (decoded, size) = Edit::decode(&log, file, 0).unwrap().unwrap();
+        assert_eq!((decoded, size), (flush.clone(), flush.encode().len()));
+        let (decoded, _) = Edit::decode(&log[size..], file, size as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded, compaction);
+        // a torn tail
+        assert!(Edit::decode(&log[size..log.len() - 1], file, 0)
+            .unwrap()
+            .is_none());
+        let mut damaged = log.clone();
+        damaged[10] ^= 1;
+        assert!(Edit::decode(&damaged, file, 0).is_err());
+
+        let mut version = Version::default();
+        version.apply(&flush);
+        version.apply(&compaction);
+        assert!(version.levels[0].is_empty());
+        assert_eq!(version.levels[1].len(), 2);
+        assert!(version.contains("data.1.2") && !version.contains("data.0.0"));
+        assert_eq!(version.snapshot().added, compaction.added);
+
+        let truncation = Edit {
+            added: vec![],
+            removed: vec!["data.1.1".to_string(), "data.1.2".to_string()],
+            cleared: Some(42),
+        };
+        let (decoded, _) = Edit::decode(&truncation.encode(), file, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded, truncation);
+        version.apply(&truncation);
+        assert!(version.levels.iter().all(Vec::is_empty));
+        assert_eq!(version.snapshot().cleared, Some(42));
+    }
+}
@@ -1,6 +1,14 @@
+mod batch;
+mod bloom;
+mod cache;
+mod compaction;
 mod fs;
 mod index;
-pub(crate) use self::storage::Storage;
+mod manifest;
+mod options;
+mod segment;
+pub(crate) use self::storage::{Snapshot, Storage};
+pub(crate) use data_format::Record;
 use crate::error::Result;
 use crate::response::Response;
 use crate::storage::index::Index;
@@ -15,20 +23,7 @@ use std::collections::BTreeMap;
 use std::path::PathBuf;
 mod storage;
 use fs::FS;
-pub use fs::FileSystem;
-
-pub struct Builder<T: FS> {
-    storage: Storage<T>,
-}
-
-//impl Builder {
-//    pub fn new() -> Self {
-//        Self {
-//            storage: Storage::new("/tmp", "data").unwrap(),
-//        }
-//    }
-//
-//    pub fn fs<T: FS>(fs: T) -> &mut Self {
-//
-//    }
-//}
+pub use fs::{FileSystem, MemoryFileSystem};
+pub(crate) use fs::sync_dir;
+pub use batch::WriteBatch;
+pub use options::{Builder, Compression, Options, ReadMode, SyncPolicy};
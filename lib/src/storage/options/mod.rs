@@ -0,0 +1,273 @@
+use crate::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+/// when the write-ahead log is flushed to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// every write is synced before it is acknowledged
+    Always,
+    /// leave it to the OS, a crash of the machine may lose the latest writes,
+    /// a crash of the server alone loses nothing
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => Err(Error {
+                kind: ErrorKind::Options,
+            }),
+        }
+    }
+}
+
+/// how the blocks of the segments are read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadMode {
+    /// seek and read the file
+    Buffered,
+    /// map the segments into memory, a block is sliced out of the map without a syscall
+    Mmap,
+}
+
+impl FromStr for ReadMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "buffered" => Ok(ReadMode::Buffered),
+            "mmap" => Ok(ReadMode::Mmap),
+            _ => Err(Error {
+                kind: ErrorKind::Options,
+            }),
+        }
+    }
+}
+
+/// how the blocks of the new segments are compressed, every segment records its own codec,
+/// so the segments written with another one stay readable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// LZ4, cheap enough to be paid on every block read
+    Lz4,
+}
+
+impl Compression {
+    // the byte recorded in the header of a segment
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(Error {
+                kind: ErrorKind::Options,
+            }),
+        }
+    }
+}
+
+/// the tunables of a storage, see `Builder`
+#[derive(Clone, Debug)]
+pub struct Options {
+    // the memtable is flushed into a segment once it holds more bytes than this
+    pub(crate) memtable_size: usize,
+    // the base name of the segment files, for example, data.0, data.1, ...
+    pub(crate) file: String,
+    pub(crate) sync: SyncPolicy,
+    // the false-positive rate of the Bloom filter of every new segment,
+    // a lower rate takes more bits per key
+    pub(crate) bloom_false_positive_rate: f64,
+    // compaction splits its output into segments of roughly this many bytes
+    pub(crate) segment_size: u64,
+    // compact level 0 into level 1 when it holds this many segments
+    pub(crate) compaction_trigger: usize,
+    // compact a level into the next one when it takes more bytes than this,
+    // level 1 may take `level_size_base`, every further level `level_size_multiplier` times more
+    pub(crate) level_size_base: u64,
+    pub(crate) level_size_multiplier: u64,
+    // the decoded blocks kept in memory take at most this many bytes
+    pub(crate) block_cache_size: usize,
+    // the segment files kept open
+    pub(crate) open_files: usize,
+    pub(crate) read_mode: ReadMode,
+    pub(crate) compression: Compression,
+}
+
+impl Options {
+    /// the size a level may grow to before it is compacted into the next one
+    pub(crate) fn level_size(&self, level: usize) -> u64 {
+        let mut size = self.level_size_base;
+        for _ in 1..level {
+            size = size.saturating_mul(self.level_size_multiplier);
+        }
+        size
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            memtable_size: 4 << 20,
+            file: "data".to_string(),
+            sync: SyncPolicy::Always,
+            bloom_false_positive_rate: 0.01,
+            segment_size: 2 << 20,
+            compaction_trigger: 4,
+            level_size_base: 10 << 20,
+            level_size_multiplier: 10,
+            block_cache_size: 8 << 20,
+            open_files: 256,
+            read_mode: ReadMode::Buffered,
+            compression: Compression::None,
+        }
+    }
+}
+
+/// build the options of a storage, starting from the defaults:
+///
+/// ```ignore
+/// let options = Builder::new()
+///     .memtable_size(64 << 20)
+///     .sync(SyncPolicy::Never)
+///     .build()?;
+/// ```
+#[derive(Default)]
+pub struct Builder {
+    options: Options,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.options.memtable_size = bytes;
+        self
+    }
+
+    pub fn file(mut self, name: &str) -> Self {
+        self.options.file = name.to_string();
+        self
+    }
+
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.options.sync = sync;
+        self
+    }
+
+    pub fn bloom_false_positive_rate(mut self, rate: f64) -> Self {
+        self.options.bloom_false_positive_rate = rate;
+        self
+    }
+
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.options.segment_size = bytes;
+        self
+    }
+
+    pub fn compaction_trigger(mut self, segments: usize) -> Self {
+        self.options.compaction_trigger = segments;
+        self
+    }
+
+    pub fn level_size_base(mut self, bytes: u64) -> Self {
+        self.options.level_size_base = bytes;
+        self
+    }
+
+    pub fn level_size_multiplier(mut self, multiplier: u64) -> Self {
+        self.options.level_size_multiplier = multiplier;
+        self
+    }
+
+    /// 0 turns the block cache off
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.options.block_cache_size = bytes;
+        self
+    }
+
+    pub fn open_files(mut self, files: usize) -> Self {
+        self.options.open_files = files;
+        self
+    }
+
+    pub fn read_mode(mut self, mode: ReadMode) -> Self {
+        self.options.read_mode = mode;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// reject the values the storage can't work with
+    pub fn build(self) -> Result<Options> {
+        let options = self.options;
+        let valid = options.memtable_size > 0
+            // the base name is joined to the directory, and the number is its extension
+            && !options.file.is_empty()
+            && !options.file.contains(['/', '.'])
+            && options.file != "wal"
+            && options.bloom_false_positive_rate > 0.0
+            && options.bloom_false_positive_rate < 1.0
+            && options.segment_size > 0
+            && options.compaction_trigger > 0
+            && options.level_size_base > 0
+            && options.level_size_multiplier > 1
+            && options.open_files > 0;
+        if !valid {
+            return Err(Error {
+                kind: ErrorKind::Options,
+            });
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build() {
+        let options = Builder::new()
+            .memtable_size(64)
+            .sync(SyncPolicy::Never)
+            .build()
+            .unwrap();
+        assert_eq!(options.memtable_size, 64);
+        assert_eq!(options.file, "data");
+        assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+        assert_eq!("lz4".parse::<Compression>().unwrap(), Compression::Lz4);
+        assert!("zip".parse::<Compression>().is_err());
+        assert!(Builder::new().memtable_size(0).build().is_err());
+        assert!(Builder::new().file("wal").build().is_err());
+        assert!(Builder::new()
+            .bloom_false_positive_rate(1.0)
+            .build()
+            .is_err());
+        assert_eq!(Builder::new().build().unwrap().level_size(3), 1000 << 20);
+    }
+}
@@ -0,0 +1,573 @@
+use super::bloom::{self, BloomFilter};
+use super::cache::Cache;
+use super::data_format::{self, Record};
+use super::fs::{RandomAccess, WritableFile, MAGIC, META_SIZE};
+use super::index::{BlockHandle, Index};
+use super::options::{Compression, Options};
+use crate::{utils::eq_u8, Error, ErrorKind, Result};
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// tells the segments apart in the cache, a file name may be reused once its segment is removed
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// records are packed into blocks of roughly this many bytes,
+/// a lookup reads exactly one block
+pub const BLOCK_SIZE: usize = 4096;
+pub const FOOTER_SIZE: u64 = 56 + data_format::CRC_SIZE as u64;
+/// the magic of the segments which record their codec, right after it
+pub static MAGIC_V2: &[u8] = "dpdb-feff-1234-2".as_bytes();
+pub const HEADER_SIZE: u64 = META_SIZE + 1;
+
+// the layout of a segment is: MAGIC_V2|codec|block|...|block|index|filter|footer
+// a block is a run of sorted records, see `data_format`, compressed with the codec,
+// the segments written before the codec was recorded start with MAGIC alone, their blocks are plain,
+// the index holds the first key and the position of every block,
+// the filter is a Bloom filter over all the keys of the segment,
+// the footer is fixed-size, so it can be found from the end of the file.
+// Each of them ends with a checksum
+pub struct Footer {
+    pub index_offset: u64,
+    pub index_size: u64,
+    pub filter_offset: u64,
+    pub filter_size: u64,
+    pub records: u64,
+    // the largest sequence number of the records
+    pub max_seq: u64,
+    // the earliest expiry of the records, 0 if none of them expires
+    pub expires: u64,
+}
+
+impl Footer {
+    fn encode(&self) -> Vec<u8> {
+        data_format::seal(
+            &[
+                self.index_offset.to_be_bytes(),
+                self.index_size.to_be_bytes(),
+                self.filter_offset.to_be_bytes(),
+                self.filter_size.to_be_bytes(),
+                self.records.to_be_bytes(),
+                self.max_seq.to_be_bytes(),
+                self.expires.to_be_bytes(),
+            ]
+            .concat(),
+        )
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        Ok(Footer {
+            index_offset: u64::from_be_bytes(buf[..8].try_into()?),
+            index_size: u64::from_be_bytes(buf[8..16].try_into()?),
+            filter_offset: u64::from_be_bytes(buf[16..24].try_into()?),
+            filter_size: u64::from_be_bytes(buf[24..32].try_into()?),
+            records: u64::from_be_bytes(buf[32..40].try_into()?),
+            max_seq: u64::from_be_bytes(buf[40..48].try_into()?),
+            expires: u64::from_be_bytes(buf[48..56].try_into()?),
+        })
+    }
+}
+
+// the block sits at `offset` of `file`
+fn decode_block(
+    block: &[u8],
+    file: &Path,
+    offset: u64,
+    compression: Compression,
+) -> Result<Vec<Record>> {
+    let block = data_format::unseal(block, file, offset)?;
+    let block = match compression {
+        Compression::None => Cow::Borrowed(block),
+        Compression::Lz4 => Cow::Owned(
+            lz4_flex::decompress_size_prepended(block)
+                .map_err(|_| Error::corruption(file, offset))?,
+        ),
+    };
+    let mut records = vec![];
+    let mut pos = 0;
+    while pos < block.len() {
+        let at = offset + pos as u64;
+        // the checksum of the block holds, so a record cut short is not a torn write
+        let record = data_format::decode(&block[pos..], file, at)?
+            .ok_or_else(|| Error::corruption(file, at))?;
+        pos += record.size();
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// an immutable segment file, only its sparse index and its filter are held in memory
+pub struct Segment {
+    pub id: u64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub footer: Footer,
+    // the smallest and the largest key in the segment
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    index: Index,
+    filter: BloomFilter,
+    file: Arc<dyn RandomAccess>,
+    // the codec of the blocks
+    compression: Compression,
+}
+
+impl Segment {
+    /// a segment without a valid footer is reported as `ErrorKind::File`,
+    /// that is what a crash in the middle of writing it leaves behind,
+    /// anything wrong behind a valid footer is `ErrorKind::Corruption`
+    pub fn open(path: &Path, file: Arc<dyn RandomAccess>) -> Result<Self> {
+        let malformed = || Error {
+            kind: ErrorKind::File,
+        };
+        let size = file.size();
+        if size < META_SIZE + FOOTER_SIZE {
+            return Err(malformed());
+        }
+        let magic = file.read_at(0, META_SIZE)?;
+        let (compression, header_size) = if eq_u8(MAGIC, &magic) {
+            (Compression::None, META_SIZE)
+        } else if eq_u8(MAGIC_V2, &magic) {
+            let codec = file.read_at(META_SIZE, 1)?;
+            let compression = codec
+                .first()
+                .and_then(|&id| Compression::from_id(id))
+                .ok_or_else(malformed)?;
+            (compression, HEADER_SIZE)
+        } else {
+            return Err(malformed());
+        };
+        let footer = file.read_at(size - FOOTER_SIZE, FOOTER_SIZE)?;
+        let footer = Footer::decode(
+            data_format::unseal(&footer, path, size - FOOTER_SIZE).map_err(|_| malformed())?,
+        )?;
+        if footer.index_offset < header_size
+            || footer.index_offset.checked_add(footer.index_size) != Some(footer.filter_offset)
+            || footer.filter_offset.checked_add(footer.filter_size) != Some(size - FOOTER_SIZE)
+        {
+            return Err(Error::corruption(path, size - FOOTER_SIZE));
+        }
+        let index = file.read_at(footer.index_offset, footer.index_size)?;
+        let index = Index::decode(data_format::unseal(&index, path, footer.index_offset)?)
+            .map_err(|_| Error::corruption(path, footer.index_offset))?;
+        let filter = file.read_at(footer.filter_offset, footer.filter_size)?;
+        let filter = BloomFilter::decode(data_format::unseal(&filter, path, footer.filter_offset)?)
+            .map_err(|_| Error::corruption(path, footer.filter_offset))?;
+        // the largest key is the last one of the last block
+        let (smallest, largest) = match index.internal.last() {
+            Some((_, handle)) => {
+                let block = file.read_at(handle.offset, handle.size)?;
+                let last = decode_block(&block, path, handle.offset, compression)?
+                    .pop()
+                    .ok_or_else(|| Error::corruption(path, handle.offset))?;
+                (index.internal[0].0.clone(), last.key)
+            }
+            None => (vec![], vec![]),
+        };
+        Ok(Segment {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            path: path.to_path_buf(),
+            size,
+            footer,
+            smallest,
+            largest,
+            index,
+            filter,
+            file,
+            compression,
+        })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.file.is_mapped()
+    }
+
+    /// whether the key range of the segment overlaps [smallest, largest]
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.footer.records > 0
+            && self.smallest.as_slice() <= largest
+            && smallest <= self.largest.as_slice()
+    }
+
+    // a block is verified and decoded once, then served from the cache until it is evicted
+    fn read_block(&self, handle: &BlockHandle, cache: &Cache) -> Result<Arc<Vec<Record>>> {
+        let key = (self.id, handle.offset);
+        if let Some(records) = cache.block(&key) {
+            return Ok(records);
+        }
+        let block = self.file.read_at(handle.offset, handle.size)?;
+        let records = Arc::new(decode_block(
+            &block,
+            &self.path,
+            handle.offset,
+            self.compression,
+        )?);
+        cache.insert_block(key, records.clone(), handle.size as usize);
+        Ok(records)
+    }
+
+    /// the record of the key, a tombstone is a record as well,
+    /// None when the key has never been written into this segment
+    pub fn get(&self, key: &[u8], cache: &Cache) -> Result<Option<Record>> {
+        // most lookups of a missing key stop here, without reading any block
+        if !self.overlaps(key, key) || !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        let handle = match self.index.get(key) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        Ok(self
+            .read_block(handle, cache)?
+            .iter()
+            .find(|record| eq_u8(&record.key, key))
+            .cloned())
+    }
+
+    /// iterate the records, starting from the block that may hold `start`,
+    /// the keys before `start` in that block are yielded as well
+    pub fn iter_from(&self, start: &[u8]) -> Result<DBFile> {
+        Ok(DBFile {
+            path: self.path.clone(),
+            file: self.file.clone(),
+            blocks: self.index.blocks_from(start).into_iter(),
+            records: vec![].into_iter(),
+            compression: self.compression,
+        })
+    }
+
+    pub fn iter(&self) -> Result<DBFile> {
+        self.iter_from(&[])
+    }
+}
+
+/// iterate the records of a segment in key order, one block at a time,
+/// a block that fails its checksum ends the iteration with an error
+pub struct DBFile {
+    path: PathBuf,
+    file: Arc<dyn RandomAccess>,
+    blocks: std::vec::IntoIter<BlockHandle>,
+    records: std::vec::IntoIter<Record>,
+    compression: Compression,
+}
+
+impl DBFile {
+    fn read_block(&mut self, handle: &BlockHandle) -> Result<Vec<Record>> {
+        let block = self.file.read_at(handle.offset, handle.size)?;
+        decode_block(&block, &self.path, handle.offset, self.compression)
+    }
+}
+
+impl Iterator for DBFile {
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(Ok(record));
+            }
+            let handle = self.blocks.next()?;
+            match self.read_block(&handle) {
+                Ok(records) => self.records = records.into_iter(),
+                Err(e) => {
+                    self.blocks = vec![].into_iter();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// write sorted records into a new segment, the segment is usable once `finish` returns,
+/// see `WritableFile`
+pub struct SegmentWriter {
+    path: PathBuf,
+    file: Box<dyn WritableFile>,
+    // where the next block starts
+    offset: u64,
+    block: Vec<u8>,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Index,
+    // the hashes of the keys, the filter is built once their number is known
+    hashes: Vec<u64>,
+    false_positive_rate: f64,
+    compression: Compression,
+    records: u64,
+    max_seq: u64,
+    expires: Option<u64>,
+}
+
+impl SegmentWriter {
+    /// write into `file`, which is created at `path`,
+    /// the filter and the codec of the segment are taken from `options`
+    pub fn new(path: &Path, mut file: Box<dyn WritableFile>, options: &Options) -> Result<Self> {
+        file.write_all(MAGIC_V2)?;
+        file.write_all(&[options.compression.id()])?;
+        Ok(SegmentWriter {
+            path: path.to_path_buf(),
+            file,
+            offset: HEADER_SIZE,
+            block: vec![],
+            first_key: vec![],
+            last_key: vec![],
+            index: Index::new(),
+            hashes: vec![],
+            false_positive_rate: options.bloom_false_positive_rate,
+            compression: options.compression,
+            records: 0,
+            max_seq: 0,
+            expires: None,
+        })
+    }
+
+    pub fn add(&mut self, record: &Record) -> Result<()> {
+        if self.block.is_empty() {
+            self.first_key = record.key.clone();
+        }
+        self.block.extend_from_slice(&data_format::encode(record));
+        self.hashes.push(bloom::hash(&record.key));
+        self.last_key = record.key.clone();
+        self.records += 1;
+        self.max_seq = self.max_seq.max(record.seq);
+        if let Some(expires) = record.expires {
+            self.expires = Some(self.expires.map_or(expires, |e| e.min(expires)));
+        }
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// the bytes taken by the records added so far
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = match self.compression {
+            Compression::None => data_format::seal(&self.block),
+            Compression::Lz4 => data_format::seal(&lz4_flex::compress_prepend_size(&self.block)),
+        };
+        self.file.write_all(&block)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: block.len() as u64,
+        };
+        self.index.push(&self.first_key, handle);
+        self.offset += handle.size;
+        self.block.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Segment> {
+        self.finish_block()?;
+        let index = data_format::seal(&self.index.encode());
+        self.file.write_all(&index)?;
+        let mut filter = BloomFilter::new(self.hashes.len(), self.false_positive_rate);
+        for h in &self.hashes {
+            filter.add_hash(*h);
+        }
+        let filter_block = data_format::seal(&filter.encode());
+        self.file.write_all(&filter_block)?;
+        let footer = Footer {
+            index_offset: self.offset,
+            index_size: index.len() as u64,
+            filter_offset: self.offset + index.len() as u64,
+            filter_size: filter_block.len() as u64,
+            records: self.records,
+            max_seq: self.max_seq,
+            expires: self.expires.unwrap_or(0),
+        };
+        self.file.write_all(&footer.encode())?;
+        let file = self.file.finish()?;
+        Ok(Segment {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            path: self.path,
+            size: footer.filter_offset + footer.filter_size + FOOTER_SIZE,
+            footer,
+            smallest: self
+                .index
+                .internal
+                .first()
+                .map(|(key, _)| key.clone())
+                .unwrap_or_default(),
+            largest: self.last_key,
+            index: self.index,
+            filter,
+            file,
+            compression: self.compression,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::fs::{FileSystem, MemoryFileSystem, FS};
+    use super::super::options::ReadMode;
+    use super::*;
+
+    fn file_system(dir: &Path, read_mode: ReadMode) -> FileSystem {
+        let options = Options {
+            read_mode,
+            ..Default::default()
+        };
+        FileSystem::new(dir.to_str().unwrap(), &options).unwrap()
+    }
+
+    #[test]
+    fn segment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fs = file_system(tmp.path(), ReadMode::Buffered);
+        let path = tmp.path().join("data.0");
+        let file = fs.create(&path).unwrap();
+        let mut writer = SegmentWriter::new(&path, file, &Options::default()).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{:04}", i).into_bytes();
+            let mut record = match i % 10 {
+                0 => Record::new(key, i, None),
+                _ => Record::new(key, i, Some(b"value".to_vec())),
+            };
+            if i % 10 == 5 {
+                record.expires = Some(1000 + i);
+            }
+            writer.add(&record).unwrap();
+        }
+        // the segment takes its name once it is complete
+        assert!(!path.exists());
+        writer.finish().unwrap();
+        assert!(!path.with_extension("0.tmp").exists());
+
+        let segment = Segment::open(&path, fs.open(&path).unwrap()).unwrap();
+        let cache = Cache::new(1 << 20);
+        assert_eq!(segment.footer.records, 1000);
+        assert_eq!(segment.footer.max_seq, 999);
+        assert_eq!(segment.footer.expires, 1005);
+        assert_eq!(segment.smallest, b"key0000");
+        assert_eq!(segment.largest, b"key0999");
+        assert!(segment.index.internal.len() > 1);
+        let record = segment.get(b"key0421", &cache).unwrap().unwrap();
+        assert_eq!((record.value, record.seq), (b"value".to_vec(), 421));
+        assert!(segment.get(b"key0420", &cache).unwrap().unwrap().tombstone);
+        assert!(segment.get(b"key0421x", &cache).unwrap().is_none());
+        assert!(segment.get(b"a", &cache).unwrap().is_none());
+        // key0420 is in the same block as key0421
+        assert!(cache.stats().contains(&("block_cache_hits".to_string(), 1)));
+        let record = segment.get(b"key0425", &cache).unwrap().unwrap();
+        assert_eq!(record.expires, Some(1425));
+        assert!(segment.filter.may_contain(b"key0421"));
+        assert!(!segment.filter.may_contain(b"key0421x"));
+        assert_eq!(segment.iter().unwrap().count(), 1000);
+        let first = segment
+            .iter_from(b"key0500")
+            .unwrap()
+            .map(|r| r.unwrap())
+            .find(|r| r.key >= b"key0500".to_vec());
+        assert_eq!(first.unwrap().key, b"key0500");
+
+        // the map is read without opening the file
+        let fs = file_system(tmp.path(), ReadMode::Mmap);
+        let mapped = Segment::open(&path, fs.open(&path).unwrap()).unwrap();
+        assert!(mapped.is_mapped());
+        let cache = Cache::new(0);
+        let record = mapped.get(b"key0421", &cache).unwrap().unwrap();
+        assert_eq!(record.value, b"value");
+        assert_eq!(mapped.iter().unwrap().count(), 1000);
+        assert!(fs.stats().contains(&("file_cache_misses".to_string(), 0)));
+    }
+
+    #[test]
+    fn corruption() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fs = file_system(tmp.path(), ReadMode::Buffered);
+        let path = tmp.path().join("data.0");
+        let file = fs.create(&path).unwrap();
+        let mut writer = SegmentWriter::new(&path, file, &Options::default()).unwrap();
+        writer
+            .add(&Record::new(b"needle".to_vec(), 0, Some(b"hay".to_vec())))
+            .unwrap();
+        // the first block is left alone when the segment is opened, only the last one is read
+        for i in 0..1000 {
+            let key = format!("pin{:04}", i).into_bytes();
+            writer
+                .add(&Record::new(key, i, Some(b"cushion".to_vec())))
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        // the value of the first record
+        bytes[HEADER_SIZE as usize + data_format::HEADER_SIZE + 6] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let segment = Segment::open(&path, fs.open(&path).unwrap()).unwrap();
+        assert!(matches!(
+            segment.get(b"needle", &Cache::new(1 << 20)),
+            Err(Error {
+                kind: ErrorKind::Corruption { offset, .. }
+            }) if offset == HEADER_SIZE
+        ));
+        let mut iter = segment.iter().unwrap();
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+
+        // a torn footer
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(
+            Segment::open(&path, fs.open(&path).unwrap()),
+            Err(Error {
+                kind: ErrorKind::File
+            })
+        ));
+    }
+
+    #[test]
+    fn compression() {
+        let fs = MemoryFileSystem::new(&Options::default());
+        let cache = Cache::new(1 << 20);
+        let value = br#"{"name": "needle", "tags": ["sharp", "sharp", "sharp"]}"#;
+        let write = |name: &str, compression| {
+            let path = fs.dir().join(name);
+            let options = Options {
+                compression,
+                ..Default::default()
+            };
+            let file = fs.create(&path).unwrap();
+            let mut writer = SegmentWriter::new(&path, file, &options).unwrap();
+            for i in 0..1000 {
+                let key = format!("key{:04}", i).into_bytes();
+                writer
+                    .add(&Record::new(key, i, Some(value.to_vec())))
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+            Segment::open(&path, fs.open(&path).unwrap()).unwrap()
+        };
+        let plain = write("data.0", Compression::None);
+        let compressed = write("data.1", Compression::Lz4);
+        assert_eq!(compressed.compression, Compression::Lz4);
+        assert!(compressed.size < plain.size / 2);
+        let record = compressed.get(b"key0421", &cache).unwrap().unwrap();
+        assert_eq!(record.value, value);
+        assert_eq!(compressed.iter().unwrap().count(), 1000);
+
+        // a segment of the layout before the codec was recorded, MAGIC alone
+        let path = fs.dir().join("data.2");
+        let file = fs.create(&path).unwrap();
+        let mut writer = SegmentWriter::new(&path, file, &Options::default()).unwrap();
+        writer.file = fs.create(&path).unwrap();
+        writer.file.write_all(MAGIC).unwrap();
+        writer.offset = META_SIZE;
+        writer
+            .add(&Record::new(b"needle".to_vec(), 0, Some(b"hay".to_vec())))
+            .unwrap();
+        writer.finish().unwrap();
+        let old = Segment::open(&path, fs.open(&path).unwrap()).unwrap();
+        assert_eq!(old.compression, Compression::None);
+        let record = old.get(b"needle", &cache).unwrap().unwrap();
+        assert_eq!(record.value, b"hay");
+    }
+}
@@ -1,66 +1,291 @@
-use super::data_format;
-use super::fs::FS;
-use super::fs::{DBFile, FileSystem};
+use super::batch::WriteBatch;
+use super::cache::Cache;
+use super::compaction::{self, Compaction, Merger};
+use super::data_format::Record;
+use super::fs::{data_file_number, FileSystem, WritableFile, FS};
+use super::manifest::{self, Edit, SegmentMeta, Version};
+use super::options::Options;
+use super::segment::{Segment, SegmentWriter};
 use crate::error::Result;
 use crate::response::Response;
-use crate::storage::index::Index;
-use crate::utils::eq_u8;
+use crate::utils::now;
 use crate::{Error, ErrorKind};
-use log::info;
-use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{prelude::*, SeekFrom};
-use std::path::Path;
-use std::path::PathBuf;
-
-// todo: better naming? FS: FileSystem
-pub struct Storage<T: FS> {
-    index: Index,
-    memtable: BTreeMap<Vec<u8>, Vec<u8>>,
-    // when the size of memtable reaches a certain threshold,
-    // (in this case, 1 Byte, for education and test purpose)
-    // (or, todo: a configurable value)
+use log::{info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// the latest record of every key, a deleted key is kept as a tombstone until it reaches the segments
+type Memtable = BTreeMap<Vec<u8>, Record>;
+
+/// a point-in-time view of a storage: the reads through it see the writes up to `seq`,
+/// and none of the writes after it. It holds on to the memtables and the segments of that moment,
+/// see `Storage::snapshot`
+#[derive(Clone)]
+pub struct Snapshot {
+    pub seq: u64,
+    memtable: Arc<Memtable>,
+    immutable: Option<Arc<Memtable>>,
+    levels: Vec<Vec<Arc<Segment>>>,
+    _pin: Arc<()>,
+}
+
+// what a read looks at: the memtables from the youngest to the oldest, and the segments
+struct View<'a> {
+    memtables: Vec<&'a Memtable>,
+    levels: &'a [Vec<Arc<Segment>>],
+    cache: &'a Cache,
+}
+
+pub struct Storage {
+    // the live segments by level, each level from the oldest to the youngest,
+    // level 0 holds the flushed memtables, which overlap each other,
+    // the deeper levels hold disjoint key ranges, and are older than the shallower ones
+    levels: Vec<Vec<Arc<Segment>>>,
+    // shared with the snapshots taken since its last change, the next write copies it then
+    memtable: Arc<Memtable>,
+    // a full memtable, frozen while it is written into a segment in the background,
+    // it is younger than the segments and older than the memtable
+    immutable: Option<Arc<Memtable>>,
+    // the thread writing the immutable memtable
+    flushing: Option<JoinHandle<Result<Segment>>>,
+    // when the size of memtable reaches `options.memtable_size`,
     // the memtable will be migrated to the disk
-    threshold: usize,
     // but do we really want to be that accurate? counting bytes?
     // I prefer counting entries, but what to count by is trivial.
     memtable_size: usize,
-    pub fs: T,
+    // the sequence number of the latest write
+    seq: u64,
+    // the writes up to this sequence number were truncated, the log may still hold them
+    cleared: u64,
+    // the number of the next segment file, the younger segment has the bigger number, see `FileSystem`.
+    // They are handed out here, a scan of the directory would miss the file of a running flush
+    next_file: AtomicUsize,
+    // cloned into every snapshot, the segments are not compacted while any of them is alive
+    pins: Arc<()>,
+    // the blocks and the open files of the segments
+    cache: Cache,
+    options: Options,
+    // every file goes through it
+    pub fs: Box<dyn FS>,
 }
 
-impl<T: FS> Storage<T> {
-    pub fn new(dir: &str, file: &str) -> Result<Storage<T>> {
-        Ok(Storage {
-            index: Index::new(),
-            memtable: BTreeMap::new(),
-            fs: T::new(dir)?,
-            threshold: 64,
+impl Storage {
+    /// open the storage in the directory
+    pub fn new(dir: &str, options: Options) -> Result<Storage> {
+        let fs = FileSystem::new(dir, &options)?;
+        Storage::with_fs(Box::new(fs), options)
+    }
+
+    /// open the storage on the files of `fs`, for example a `MemoryFileSystem`
+    pub fn with_fs(fs: Box<dyn FS>, options: Options) -> Result<Storage> {
+        let mut storage = Storage {
+            levels: vec![vec![]],
+            memtable: Default::default(),
+            immutable: None,
+            flushing: None,
+            fs,
             memtable_size: 0,
-        })
+            seq: 0,
+            cleared: 0,
+            next_file: AtomicUsize::new(0),
+            pins: Arc::new(()),
+            cache: Cache::new(options.block_cache_size),
+            options,
+        };
+        storage.reload()?;
+        Ok(storage)
+    }
+
+    // read the files `fs` points at, the log is replayed on top of the segments, its records are younger
+    fn reload(&mut self) -> Result<()> {
+        self.memtable = Default::default();
+        self.immutable = None;
+        self.memtable_size = 0;
+        self.restore_index()?;
+        self.replay_wal()
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.fs.dir()
+    }
+
+    /// move the files to `dir`, an empty directory, and go on there.
+    /// The memtable is flushed first, the log moves along with the segments and the manifest
+    pub fn move_to(&mut self, dir: &str) -> Result<()> {
+        self.leave()?;
+        self.fs.move_dir(Path::new(dir))?;
+        self.reload()
+    }
+
+    /// go on with the storage in `dir`, a new one if the directory holds none.
+    /// The memtable is flushed first, so the files left behind are complete on their own
+    pub fn attach_to(&mut self, dir: &str) -> Result<()> {
+        self.leave()?;
+        let old = self.fs.dir().to_path_buf();
+        self.fs.attach_dir(Path::new(dir))?;
+        if let Err(e) = self.reload() {
+            // back to where the storage was
+            self.fs.attach_dir(&old)?;
+            self.reload()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // the snapshots read the segments where they are, so the files stay put while any of them is alive
+    fn leave(&mut self) -> Result<()> {
+        if self.snapshots() > 0 {
+            return Err(Error {
+                kind: ErrorKind::Snapshot,
+            });
+        }
+        self.flush()
+    }
+
+    // the log holds exactly what the memtable held when the runtime went down,
+    // so feed it back without logging it again
+    fn replay_wal(&mut self) -> Result<()> {
+        let records = self.fs.replay_wal()?;
+        info!(
+            "replaying {} records from the write-ahead log",
+            records.len()
+        );
+        for record in records.into_iter().filter(|r| r.seq > self.cleared) {
+            if !self.memtable.contains_key(&record.key) {
+                self.memtable_size += record.klen + record.vlen;
+            }
+            self.seq = self.seq.max(record.seq);
+            Arc::make_mut(&mut self.memtable).insert(record.key.clone(), record);
+        }
+        // flushing in the middle of the replay would truncate records that are not yet applied,
+        // so do it once all of them are in the memtable
+        if self.memtable_size > self.options.memtable_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // migrate the memtable into a new segment, and wait until it is done
+    fn flush(&mut self) -> Result<()> {
+        self.freeze()?;
+        self.wait_flush()
+    }
+
+    // swap in an empty memtable and write the full one in the background,
+    // only one memtable is frozen at a time, so wait for the previous flush first
+    fn freeze(&mut self) -> Result<()> {
+        self.wait_flush()?;
+        // the last flush failed, the frozen memtable is still waiting for its segment
+        if self.immutable.is_some() {
+            self.spawn_flush()?;
+            self.wait_flush()?;
+        }
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        // the records of the frozen memtable stay in the frozen log until they are durable
+        self.fs.rotate_wal()?;
+        self.immutable = Some(std::mem::take(&mut self.memtable));
+        self.memtable_size = 0;
+        self.spawn_flush()
+    }
+
+    fn spawn_flush(&mut self) -> Result<()> {
+        let memtable = match &self.immutable {
+            Some(memtable) => memtable.clone(),
+            None => return Ok(()),
+        };
+        let path = self.new_data_file(0);
+        let file = self.fs.create(&path)?;
+        let options = self.options.clone();
+        self.flushing = Some(thread::spawn(move || {
+            migrate_memtable(&path, file, &memtable, &options)
+        }));
+        Ok(())
+    }
+
+    // the path of a new segment file of the level
+    fn new_data_file(&self, level: usize) -> PathBuf {
+        self.fs
+            .data_file(level, self.next_file.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // pick up the segment of the background flush if it is done, without blocking
+    fn poll_flush(&mut self) -> Result<()> {
+        match &self.flushing {
+            Some(handle) if handle.is_finished() => self.wait_flush(),
+            _ => Ok(()),
+        }
+    }
+
+    // block until the background flush is done, then swap its segment in for the immutable memtable,
+    // and compact the levels that outgrow their limits.
+    // If the flush fails, the immutable memtable and its log are kept, the next freeze retries
+    pub(crate) fn wait_flush(&mut self) -> Result<()> {
+        let handle = match self.flushing.take() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        let segment = handle.join().map_err(|_| Error {
+            kind: ErrorKind::Unknown,
+        })??;
+        // the segment is live once the manifest says so, only then is its log dropped
+        self.fs.log_edit(&Edit {
+            added: vec![SegmentMeta::of(0, &segment)],
+            removed: vec![],
+            cleared: None,
+        })?;
+        self.levels[0].push(Arc::new(segment));
+        self.immutable = None;
+        self.fs.retire_wal()?;
+        Ok(())
     }
 
     pub fn clear(&mut self) -> Result<Response> {
-        self.fs.clear()?;
-        self.index.clear();
+        self.truncate()?;
         Ok(Response::Ok)
     }
 
-    /// the layout of a pair is: len(key)|len(value)|key|value
-    pub fn migrate_memtable(&mut self, path: &Path) -> Result<()> {
-        info!("migrating memtable to disk: {}", path.display());
-        let mut file = FileSystem::open_file_safely(path)?;
-        let mut offset: u64 = T::meta_size();
-        for (key, value) in &self.memtable {
-            let buf = data_format::encode(key, value);
-            file.write_all(&buf)?;
-            offset += buf.len() as u64;
-            // todo: when the runtime crashes, find a way to restore the index
-            self.index.insert(key, path.to_str().unwrap(), offset);
-        }
-        // this is the uglyness of OOP
-        self.memtable.clear();
+    /// drop every key: the segments, the memtables and the log.
+    /// The edit of the manifest which removes the segments also marks the logged writes as gone,
+    /// so a crash keeps all of them or none, whichever step it interrupts
+    pub fn truncate(&mut self) -> Result<()> {
+        // the segment of the flush in flight is not live yet, its records are still in the frozen log
+        if let Some(handle) = self.flushing.take() {
+            if let Ok(Ok(segment)) = handle.join() {
+                self.fs.remove(&segment.path)?;
+            }
+        }
+        self.fs.log_edit(&Edit {
+            added: vec![],
+            removed: self
+                .levels
+                .iter()
+                .flatten()
+                .map(|segment| manifest::file_name(&segment.path))
+                .collect(),
+            cleared: Some(self.seq),
+        })?;
+        self.cleared = self.seq;
+        self.memtable = Default::default();
+        self.immutable = None;
         self.memtable_size = 0;
-        file.sync_all()?;
+        let old: Vec<_> = std::mem::replace(&mut self.levels, vec![vec![]])
+            .into_iter()
+            .flatten()
+            .collect();
+        // the records left in the logs are skipped by the replay from now on
+        self.fs.clear()?;
+        // the snapshots still read the old segments, the next restart removes them
+        if self.snapshots() == 0 {
+            for segment in old {
+                self.fs.remove(&segment.path)?;
+            }
+        }
         Ok(())
     }
 
@@ -68,111 +293,1223 @@ impl<T: FS> Storage<T> {
     // consider this scenario: the user reads a key immediately after inserting it to the db,
     //  the user should get the key from the memtable, rather than segment files.
     pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<Response> {
-        let prev = self.memtable.get(key);
-        if prev.is_none() {
-            // the new key triggers the flushing of memtable, but the key itself stays in memtable
-            self.memtable_size += key.len() + value.len();
-            if self.memtable_size > self.threshold {
-                // first, pick a name
-                // the work is delegated to fs who knows what files are in the data directory,
-                // underneath, the fs will do a heavy(is it?) load of file operations(mainly file renaming)
-                let file_name = self.fs.allocate_data_file()?;
-                self.migrate_memtable(&file_name)?;
-            }
-        }
-        _ = self.memtable.insert(key.to_vec(), value.to_vec());
+        self.write(key, Some(value))?;
         Ok(Response::Record {
             key: key.to_owned(),
             value: value.to_owned(),
         })
     }
 
+    // the older values in the segments stay where they are,
+    // the tombstone hides them until compaction drops them
+    pub fn del(&mut self, key: &[u8]) -> Result<Response> {
+        self.write(key, None)?;
+        Ok(Response::Ok)
+    }
+
+    fn write(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        match value {
+            Some(value) => batch.put(key, value),
+            None => batch.delete(key),
+        };
+        self.write_batch(batch)
+    }
+
+    /// apply the writes of the batch at once: they are logged as one unit, with a single sync,
+    /// and reach the memtable together, so a crash keeps all of them or none
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut records = batch.records;
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.poll_flush()?;
+        // the new keys trigger the flushing of memtable, but the keys themselves stay in memtable
+        if self.memtable_size + self.new_bytes(&records) > self.options.memtable_size
+            && !self.memtable.is_empty()
+        {
+            self.freeze()?;
+        }
+        for (record, seq) in records.iter_mut().zip(self.seq + 1..) {
+            record.seq = seq;
+        }
+        // log the keys after the freeze, otherwise they would go into the frozen log
+        self.fs.wal(&records)?;
+        // a batch which failed to be logged leaves no trace
+        self.memtable_size += self.new_bytes(&records);
+        self.seq += records.len() as u64;
+        let memtable = Arc::make_mut(&mut self.memtable);
+        for record in records {
+            memtable.insert(record.key.clone(), record);
+        }
+        Ok(())
+    }
+
+    // the bytes the records add to the memtable, a key it already holds adds nothing,
+    // nor does a key the batch repeats
+    fn new_bytes(&self, records: &[Record]) -> usize {
+        let mut keys = HashSet::new();
+        records
+            .iter()
+            .filter(|record| !self.memtable.contains_key(&record.key) && keys.insert(&record.key))
+            .map(|record| record.klen + record.vlen)
+            .sum()
+    }
+
+    /// the upkeep the writes leave behind: the levels which outgrew their limits are compacted,
+    /// then the expired values which are never read are reclaimed: the ones in the memtable turn into tombstones,
+    /// and the first segment holding any is compacted. The segments are left alone while snapshots hold on to them
+    pub fn sweep(&mut self) -> Result<()> {
+        self.poll_flush()?;
+        let now = now();
+        if self.memtable.values().any(|record| record.is_expired(now)) {
+            // the log keeps the expired records, they are just as expired when they are replayed
+            for record in Arc::make_mut(&mut self.memtable).values_mut() {
+                if record.is_expired(now) {
+                    *record = record.clone().settle(now);
+                }
+            }
+        }
+        if self.snapshots() > 0 {
+            return Ok(());
+        }
+        while let Some(compaction) = compaction::pick(&self.levels, &self.options) {
+            self.compact_level(compaction)?;
+        }
+        if let Some(compaction) = compaction::expired(&self.levels, now) {
+            self.compact_level(compaction)?;
+        }
+        Ok(())
+    }
+
+    /// a consistent view of the storage as it is now, the writes that follow don't show through it.
+    /// The segments it reads are kept as long as it lives, so compaction waits until it is dropped
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            seq: self.seq,
+            memtable: self.memtable.clone(),
+            immutable: self.immutable.clone(),
+            levels: self.levels.clone(),
+            _pin: self.pins.clone(),
+        }
+    }
+
+    // the number of live snapshots
+    fn snapshots(&self) -> usize {
+        Arc::strong_count(&self.pins) - 1
+    }
+
+    fn view(&self) -> View<'_> {
+        View {
+            memtables: std::iter::once(&*self.memtable)
+                .chain(self.immutable.as_deref())
+                .collect(),
+            levels: &self.levels,
+            cache: &self.cache,
+        }
+    }
+
+    fn view_at<'a>(&'a self, snapshot: &'a Snapshot) -> View<'a> {
+        View {
+            memtables: std::iter::once(&*snapshot.memtable)
+                .chain(snapshot.immutable.as_deref())
+                .collect(),
+            levels: &snapshot.levels,
+            cache: &self.cache,
+        }
+    }
+
     pub fn get(&self, key: &[u8]) -> Result<Response> {
-        match self.memtable.get(key) {
-            Some(value) => Ok(Response::Record {
-                key: key.to_vec(),
-                value: value.clone(),
-            }),
-            None => self.get_from_segments(key),
+        self.view().get(key)
+    }
+
+    /// the latest record of the key, None if it is deleted or expired
+    pub fn lookup(&self, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Record>> {
+        match snapshot {
+            Some(snapshot) => self.view_at(snapshot).lookup(key),
+            None => self.view().lookup(key),
         }
     }
 
-    pub fn get_from_segments(&self, key: &[u8]) -> Result<Response> {
-        let node = self.index.get(key).map(Ok).unwrap_or(Err(Error {
-            kind: ErrorKind::Key,
-        }))?;
-        let mut seg = File::open(&node.segment)?;
-        _ = seg.seek(SeekFrom::Start(node.offset));
-        let rec = FileSystem::read_record_with(&mut seg)?;
-        Ok(Response::Record {
-            key: rec.key,
-            value: rec.value,
+    /// read the key as it was when the snapshot was taken
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Result<Response> {
+        self.view_at(snapshot).get(key)
+    }
+
+    /// the live pairs whose keys fall in [start, end), in key order
+    pub fn range<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        self.view().range(start, end)
+    }
+
+    /// the pairs of [start, end) as they were when the snapshot was taken
+    pub fn range_at<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+        snapshot: &'a Snapshot,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        self.view_at(snapshot).range(start, end)
+    }
+
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: Option<usize>,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<Response> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let records = match snapshot {
+            Some(snapshot) => self
+                .range_at(start, end, snapshot)
+                .take(limit)
+                .collect::<Result<Vec<_>>>()?,
+            None => self
+                .range(start, end)
+                .take(limit)
+                .collect::<Result<Vec<_>>>()?,
+        };
+        Ok(Response::Records { records })
+    }
+
+    /// the counters of the caches, and the number of segments in every level
+    pub fn stats(&self) -> Result<Response> {
+        let mut stats = self.cache.stats();
+        stats.extend(self.fs.stats());
+        let mapped = self
+            .levels
+            .iter()
+            .flatten()
+            .filter(|s| s.is_mapped())
+            .count();
+        stats.push(("mapped_segments".to_string(), mapped as u64));
+        stats.push(("snapshots".to_string(), self.snapshots() as u64));
+        for (level, segments) in self.levels.iter().enumerate() {
+            stats.push((format!("level_{}_segments", level), segments.len() as u64));
+        }
+        Ok(Response::Records {
+            records: stats
+                .into_iter()
+                .map(|(name, value)| (name.into_bytes(), value.to_string().into_bytes()))
+                .collect(),
         })
     }
 
+    // the manifest tells the live segments, only the sparse index of each is loaded,
+    // the segments are kept from the oldest to the youngest, so lookups know which one wins.
+    // The manifest is rewritten to hold just them, and the segment files it doesn't know are removed:
+    // they were cut short by a crash, or compacted away before they could be removed
     pub fn restore_index(&mut self) -> Result<()> {
-        let files = FileSystem::scan_data_files(Path::new(&self.fs.dir()))?;
-        for file in files {
-            let db = DBFile::new(&file)?;
-            let mut offset = T::meta_size();
-            for rec in db {
-                self.index
-                    .insert(&rec.key, file.as_os_str().to_str().unwrap(), offset);
-                offset += rec.size() as u64;
+        let version = match self.fs.read_manifest()? {
+            Some(edits) => {
+                let mut version = Version::default();
+                for edit in &edits {
+                    version.apply(edit);
+                }
+                version
+            }
+            None => self.scan_segments()?,
+        };
+        self.levels = vec![vec![]];
+        for (level, segments) in version.levels.iter().enumerate() {
+            if self.levels.len() <= level {
+                self.levels.resize_with(level + 1, Vec::new);
+            }
+            for meta in segments {
+                let path = self.fs.dir().join(&meta.name);
+                let segment = Segment::open(&path, self.fs.open(&path)?)?;
+                self.levels[level].push(Arc::new(segment));
+            }
+        }
+        self.fs.reset_manifest(&version.snapshot())?;
+        let files = self.fs.data_files()?;
+        // the numbers go on after every segment file, the live ones and the ones about to be removed
+        let listed = version.levels.iter().flatten();
+        let numbers = files
+            .iter()
+            .map(|(_, file)| file.clone())
+            .chain(listed.map(|meta| PathBuf::from(&meta.name)))
+            .filter_map(|file| data_file_number(&file, &self.options.file));
+        self.next_file = AtomicUsize::new(numbers.max().map_or(0, |number| number + 1));
+        for (_, file) in files {
+            if !version.contains(&manifest::file_name(&file)) {
+                warn!(
+                    "removing segment {} unknown to the manifest",
+                    file.display()
+                );
+                self.fs.remove(&file)?;
             }
         }
+        // the numbering goes on from the latest write that reached a segment, the log may hold later ones,
+        // or from the truncation, the writes after it must not be taken for truncated ones
+        self.cleared = version.cleared;
+        self.seq = self
+            .levels
+            .iter()
+            .flatten()
+            .map(|segment| segment.footer.max_seq)
+            .max()
+            .unwrap_or(0)
+            .max(self.cleared);
+        info!(
+            "recovered {} records from {} segments in {} levels in {}",
+            self.levels
+                .iter()
+                .flatten()
+                .map(|segment| segment.footer.records)
+                .sum::<u64>(),
+            self.levels.iter().map(Vec::len).sum::<usize>(),
+            self.levels.len(),
+            self.fs.dir().display()
+        );
         Ok(())
     }
 
-    pub fn get_from_file(file: &Path, key: &[u8]) -> Result<Response> {
-        let dbfile = DBFile::new(file)?;
-        for record in dbfile {
-            if eq_u8(key, &record.key) {
-                return Ok(Response::Record {
-                    key: key.to_vec(),
-                    value: record.value,
-                });
+    // a directory written before there was a manifest, every complete segment file is live,
+    // from the oldest to the youngest by their numbers
+    fn scan_segments(&self) -> Result<Version> {
+        let mut version = Version::default();
+        for (level, file) in self.fs.data_files()? {
+            match self.fs.open(&file).and_then(|f| Segment::open(&file, f)) {
+                Ok(segment) => version.apply(&Edit {
+                    added: vec![SegmentMeta::of(level, &segment)],
+                    removed: vec![],
+                    cleared: None,
+                }),
+                // a segment without its footer was cut short by a crash,
+                // its records are still in the log, or in the segments it was compacted from
+                Err(Error {
+                    kind: ErrorKind::File,
+                }) => warn!("skipping unfinished segment {}", file.display()),
+                // anything else is damage to data that was acknowledged
+                Err(e) => return Err(e),
             }
         }
-        Err(Error {
-            kind: ErrorKind::Key,
-        })
+        Ok(version)
+    }
+
+    /// k-way merge all the segments into the deepest level, only the youngest version of each key survives.
+    /// The result is written into new segments which are younger than all the old ones,
+    /// so a crash before the old segments are removed is harmless:
+    /// the new segments hold the same values and shadow the old ones when the index is restored
+    pub fn compact(&mut self) -> Result<Response> {
+        if self.snapshots() > 0 {
+            return Err(Error {
+                kind: ErrorKind::Snapshot,
+            });
+        }
+        // the segment being flushed takes part
+        self.wait_flush()?;
+        let segments = self.levels.iter().map(Vec::len).sum::<usize>();
+        if segments == 0 {
+            return Ok(Response::Ok);
+        }
+        let bottom = self.levels.len().max(2) - 1;
+        info!("compacting {} segments into level {}", segments, bottom);
+        let sources = self
+            .levels
+            .iter()
+            .rev()
+            .flatten()
+            .map(|segment| segment.iter())
+            .collect::<Result<Vec<_>>>()?;
+        // every segment takes part in the merge,
+        // there is no older value left for the tombstones to shadow
+        let outputs = self.write_segments(Merger::new(sources), bottom, false)?;
+        self.fs.log_edit(&Edit {
+            added: outputs
+                .iter()
+                .map(|segment| SegmentMeta::of(bottom, segment))
+                .collect(),
+            removed: self
+                .levels
+                .iter()
+                .flatten()
+                .map(|segment| manifest::file_name(&segment.path))
+                .collect(),
+            cleared: None,
+        })?;
+        // the old segments are still intact, swap them out before removing them
+        let mut levels: Vec<Vec<Arc<Segment>>> = (0..bottom).map(|_| vec![]).collect();
+        levels.push(outputs);
+        let old = std::mem::replace(&mut self.levels, levels);
+        for segment in old.into_iter().flatten() {
+            self.fs.remove(&segment.path)?;
+        }
+        info!(
+            "compacted into {} segments holding {} records",
+            self.levels[bottom].len(),
+            self.levels[bottom]
+                .iter()
+                .map(|segment| segment.footer.records)
+                .sum::<u64>()
+        );
+        Ok(Response::Ok)
+    }
+
+    // merge the picked segments of a level with the ones they overlap in the next level,
+    // the result replaces them in the next level
+    fn compact_level(&mut self, compaction: Compaction) -> Result<()> {
+        let Compaction {
+            level,
+            inputs,
+            overlapped,
+        } = compaction;
+        if self.levels.len() == level + 1 {
+            self.levels.push(vec![]);
+        }
+        // the next level is older, its segments go first
+        let picked = overlapped
+            .iter()
+            .map(|&i| &*self.levels[level + 1][i])
+            .chain(inputs.iter().map(|&i| &*self.levels[level][i]))
+            .collect::<Vec<_>>();
+        let sources = picked
+            .iter()
+            .map(|segment| segment.iter())
+            .collect::<Result<Vec<_>>>()?;
+        // a tombstone can go once no deeper level holds a value for it to shadow
+        let keep_tombstones = match compaction::key_range(picked.into_iter()) {
+            Some((smallest, largest)) => self.levels[level + 2..]
+                .iter()
+                .flatten()
+                .any(|segment| segment.overlaps(&smallest, &largest)),
+            None => false,
+        };
+        info!(
+            "compacting {} segments of level {} with {} segments of level {}",
+            inputs.len(),
+            level,
+            overlapped.len(),
+            level + 1
+        );
+        let outputs = self.write_segments(Merger::new(sources), level + 1, keep_tombstones)?;
+        self.fs.log_edit(&Edit {
+            added: outputs
+                .iter()
+                .map(|segment| SegmentMeta::of(level + 1, segment))
+                .collect(),
+            removed: inputs
+                .iter()
+                .map(|&i| &self.levels[level][i])
+                .chain(overlapped.iter().map(|&i| &self.levels[level + 1][i]))
+                .map(|segment| manifest::file_name(&segment.path))
+                .collect(),
+            cleared: None,
+        })?;
+        // the old segments are still intact, swap them out before removing them
+        let mut old = vec![];
+        for &i in inputs.iter().rev() {
+            old.push(self.levels[level].remove(i));
+        }
+        for &i in overlapped.iter().rev() {
+            old.push(self.levels[level + 1].remove(i));
+        }
+        self.levels[level + 1].extend(outputs);
+        for segment in old {
+            self.fs.remove(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    // write the merged records into new segments of the level, split at `segment_size`
+    fn write_segments(
+        &self,
+        records: impl Iterator<Item = Result<Record>>,
+        level: usize,
+        keep_tombstones: bool,
+    ) -> Result<Vec<Arc<Segment>>> {
+        let now = now();
+        let mut records = records
+            .map(|record| record.map(|record| record.settle(now)))
+            .filter(|record| keep_tombstones || !matches!(record, Ok(record) if record.tombstone))
+            .peekable();
+        let mut segments = vec![];
+        while records.peek().is_some() {
+            let path = self.new_data_file(level);
+            let mut writer = SegmentWriter::new(&path, self.fs.create(&path)?, &self.options)?;
+            for record in records.by_ref() {
+                writer.add(&record?)?;
+                if writer.size() >= self.options.segment_size {
+                    break;
+                }
+            }
+            segments.push(Arc::new(writer.finish()?));
+        }
+        Ok(segments)
+    }
+}
+
+impl<'a> View<'a> {
+    fn get(&self, key: &[u8]) -> Result<Response> {
+        match self.lookup(key)? {
+            Some(record) => Ok(Response::Record {
+                key: record.key,
+                value: record.value,
+            }),
+            None => Err(Error {
+                kind: ErrorKind::Key,
+            }),
+        }
     }
 
-    pub fn get_from_files(dir: &Path, key: &[u8]) -> Result<Response> {
-        let files = FileSystem::scan_data_files(dir)?;
-        for file in files {
-            if let Ok(r) = Storage::<T>::get_from_file(&file, key) {
-                return Ok(r);
+    // the memtables are younger than the segments, and the youngest segment that knows the key
+    // holds its latest version: the shallower levels are younger, and so are the later segments of a level.
+    // A tombstone or an expired value there hides the older versions
+    fn lookup(&self, key: &[u8]) -> Result<Option<Record>> {
+        let now = now();
+        for memtable in &self.memtables {
+            if let Some(record) = memtable.get(key) {
+                return Ok(record.is_live(now).then(|| record.clone()));
             }
         }
+        for segment in self.levels.iter().flat_map(|level| level.iter().rev()) {
+            if let Some(record) = segment.get(key, self.cache)? {
+                return Ok(record.is_live(now).then_some(record));
+            }
+        }
+        Ok(None)
+    }
 
-        Err(Error {
-            kind: ErrorKind::Key,
+    // the memtables are younger than the segments, so their versions shadow theirs
+    fn range(
+        self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        // an upside-down range is empty rather than a panic
+        let end = end.max(start);
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Record>> + 'a>> = vec![];
+        for segment in self.levels.iter().rev().flatten() {
+            let (start, end) = (start.to_vec(), end.to_vec());
+            match segment.iter_from(&start) {
+                // errors are let through, the merge stops at them
+                Ok(iter) => sources.push(Box::new(
+                    iter.skip_while(
+                        move |record| matches!(record, Ok(record) if record.key < start),
+                    )
+                    .take_while(move |record| {
+                        record.as_ref().map_or(true, |record| record.key < end)
+                    }),
+                )),
+                Err(e) => sources.push(Box::new(std::iter::once(Err(e)))),
+            }
+        }
+        let bounds = (
+            Bound::Included(start.to_vec()),
+            Bound::Excluded(end.to_vec()),
+        );
+        for memtable in self.memtables.into_iter().rev() {
+            sources.push(Box::new(
+                memtable
+                    .range(bounds.clone())
+                    .map(|(_, record)| Ok(record.clone())),
+            ));
+        }
+        let now = now();
+        Merger::new(sources).filter_map(move |record| match record {
+            Ok(record) if !record.is_live(now) => None,
+            Ok(record) => Some(Ok((record.key, record.value))),
+            Err(e) => Some(Err(e)),
         })
     }
+}
 
-    /*
-    // 1. collect the entries into a sorted map
-    // 2. write to a new segment file and refresh the index
-    // the thing is, when to compact?
-    //  when the index reaches certain threshold
-    //      it will be dump to a file and compacted
-    fn compact(dir: &str, file: &str) -> Result<PathBuf> {
-        let file = Path::new(file);
-        let mut handle = FileSystem::open_file_safely(file)?;
-        let mut records = BTreeMap::new();
-        while let Ok((h, r)) = FileSystem::read_record_with(handle) {
-            handle = h;
-            records.insert(r.key, r.value);
-        }
-        // write to new file
-        let new_file = file.with_extension("new");
-        let mut storage = Storage::new(dir, new_file.to_str().unwrap())?;
-        for (k, v) in records {
-            storage.set(&k, &v)?;
-        }
-        Ok(new_file)
-    }
-    */
+// a running flush writes into the data directory, let it finish before the directory is reopened
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Err(e) = self.wait_flush() {
+            warn!("the flush of the frozen memtable failed: {}", e);
+        }
+    }
+}
+
+/// the memtable is written out as a sorted segment, see `segment` for the layout
+fn migrate_memtable(
+    path: &Path,
+    file: Box<dyn WritableFile>,
+    memtable: &Memtable,
+    options: &Options,
+) -> Result<Segment> {
+    info!("migrating memtable to disk: {}", path.display());
+    let mut writer = SegmentWriter::new(path, file, options)?;
+    let now = now();
+    for record in memtable.values() {
+        // tombstones are written as well, they shadow the values in the older segments
+        writer.add(&record.clone().settle(now))?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::fs::{log_entry, FileSystem};
+    use super::super::options::{Builder, Compression, ReadMode};
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::time::Duration;
+
+    // small enough for a handful of keys to be flushed
+    fn options() -> Options {
+        Builder::new().memtable_size(64).build().unwrap()
+    }
+
+    #[test]
+    fn replay_wal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            storage.set(b"needle", b"hay").unwrap();
+        }
+        // a half-written record left behind by a crash
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(tmp.path().join("wal"))
+            .unwrap();
+        let entry = log_entry(
+            &[Record::new(b"torn".to_vec(), 2, Some(b"record".to_vec()))],
+            false,
+        );
+        wal.write_all(&entry[..30]).unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            assert!(storage.get(b"torn").is_err());
+            storage.set(b"pin", b"cushion").unwrap();
+        }
+        let storage = Storage::new(dir, options()).unwrap();
+        assert!(matches!(
+            storage.get(b"needle").unwrap(),
+            Response::Record { value, .. } if value == b"hay"
+        ));
+        assert!(matches!(
+            storage.get(b"pin").unwrap(),
+            Response::Record { value, .. } if value == b"cushion"
+        ));
+    }
+
+    #[test]
+    fn torn_batch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"needle", b"hay").put(b"pin", b"cushion");
+            storage.write_batch(batch).unwrap();
+        }
+        // a batch cut short by a crash, none of its records is applied
+        let batch = log_entry(
+            &[
+                Record::new(b"needle".to_vec(), 3, Some(b"straw".to_vec())),
+                Record::new(b"pin".to_vec(), 4, None),
+            ],
+            false,
+        );
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(tmp.path().join("wal"))
+            .unwrap();
+        wal.write_all(&batch[..batch.len() - 1]).unwrap();
+        let storage = Storage::new(dir, options()).unwrap();
+        assert!(matches!(
+            storage.get(b"needle").unwrap(),
+            Response::Record { value, .. } if value == b"hay"
+        ));
+        assert!(storage.get(b"pin").is_ok());
+        assert_eq!(storage.seq, 2);
+
+        // a key repeated in a batch takes its place in the memtable once
+        let mut storage = storage;
+        let mut batch = WriteBatch::new();
+        batch.put(b"key", b"value").put(b"key", b"other");
+        let size = storage.memtable_size;
+        storage.write_batch(batch).unwrap();
+        assert_eq!(storage.memtable_size, size + b"keyvalue".len());
+        assert_eq!(storage.seq, 4);
+    }
+
+    #[test]
+    fn compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for round in 0..4 {
+                for i in 0..16 {
+                    storage
+                        .set(
+                            format!("key{}", i).as_bytes(),
+                            format!("value{}", round).as_bytes(),
+                        )
+                        .unwrap();
+                }
+            }
+            storage.compact().unwrap();
+            assert_eq!(
+                FileSystem::scan_data_files(tmp.path(), "data")
+                    .unwrap()
+                    .len(),
+                1
+            );
+            assert!(matches!(
+                storage.get(b"key3").unwrap(),
+                Response::Record { value, .. } if value == b"value3"
+            ));
+        }
+        let storage = Storage::new(dir, options()).unwrap();
+        for i in 0..16 {
+            assert!(matches!(
+                storage.get(format!("key{}", i).as_bytes()).unwrap(),
+                Response::Record { value, .. } if value == b"value3"
+            ));
+        }
+    }
+
+    #[test]
+    fn leveled_compaction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let options = || {
+            Builder::new()
+                .memtable_size(256)
+                .segment_size(512)
+                .compaction_trigger(2)
+                .level_size_base(1024)
+                .level_size_multiplier(2)
+                .build()
+                .unwrap()
+        };
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for round in 0..3 {
+                for i in 0..200 {
+                    // spread the keys over the whole range in every flush
+                    let key = format!("key{:03}", i * 7 % 200);
+                    storage
+                        .set(key.as_bytes(), format!("value{}", round).as_bytes())
+                        .unwrap();
+                    storage.sweep().unwrap();
+                }
+            }
+            for i in 0..10 {
+                storage.del(format!("key{:03}", i).as_bytes()).unwrap();
+            }
+            storage.wait_flush().unwrap();
+            storage.sweep().unwrap();
+            assert!(storage.levels.len() > 2);
+            for level in &storage.levels[1..] {
+                for (i, a) in level.iter().enumerate() {
+                    assert!(level[i + 1..]
+                        .iter()
+                        .all(|b| !a.overlaps(&b.smallest, &b.largest)));
+                }
+            }
+        }
+        let storage = Storage::new(dir, options()).unwrap();
+        for i in 0..200 {
+            let key = format!("key{:03}", i);
+            match i {
+                0..=9 => assert!(storage.get(key.as_bytes()).is_err()),
+                _ => assert!(matches!(
+                    storage.get(key.as_bytes()).unwrap(),
+                    Response::Record { value, .. } if value == b"value2"
+                )),
+            }
+        }
+        assert_eq!(storage.range(b"key", b"kez").count(), 190);
+    }
+
+    #[test]
+    fn del() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            storage.set(b"needle", b"hay").unwrap();
+            storage.flush().unwrap();
+            storage.del(b"needle").unwrap();
+            assert!(storage.get(b"needle").is_err());
+            storage.flush().unwrap();
+            assert!(storage.get(b"needle").is_err());
+            storage.set(b"pin", b"cushion").unwrap();
+            storage.del(b"pin").unwrap();
+        }
+        let mut storage = Storage::new(dir, options()).unwrap();
+        assert!(storage.get(b"needle").is_err());
+        assert!(storage.get(b"pin").is_err());
+        storage.compact().unwrap();
+        assert!(storage.get(b"needle").is_err());
+        assert!(storage.levels.iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn background_flush() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut storage = Storage::new(dir, options()).unwrap();
+        for i in 0..8 {
+            storage
+                .set(format!("key{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        // frozen, and readable while it is being written
+        assert!(storage.immutable.is_some());
+        assert!(matches!(
+            storage.get(b"key0").unwrap(),
+            Response::Record { value, .. } if value == b"value"
+        ));
+        assert_eq!(storage.range(b"key", b"kez").count(), 8);
+        // the file of the flush is not in the directory under its name yet, its number is taken all the same
+        let next = storage.new_data_file(1);
+        storage.wait_flush().unwrap();
+        assert!(storage.immutable.is_none());
+        assert_eq!(storage.levels[0].len(), 1);
+        let number = |path: &Path| data_file_number(path, &storage.options.file).unwrap();
+        assert!(number(&storage.levels[0][0].path) < number(&next));
+        assert!(!tmp.path().join("wal.frozen").exists());
+    }
+
+    #[test]
+    fn replay_frozen_wal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            storage.set(b"needle", b"hay").unwrap();
+        }
+        // the server went down in the middle of flushing a frozen memtable
+        std::fs::rename(tmp.path().join("wal"), tmp.path().join("wal.frozen")).unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            storage.set(b"pin", b"cushion").unwrap();
+            storage.flush().unwrap();
+        }
+        let storage = Storage::new(dir, options()).unwrap();
+        assert!(matches!(
+            storage.get(b"needle").unwrap(),
+            Response::Record { value, .. } if value == b"hay"
+        ));
+        assert!(matches!(
+            storage.get(b"pin").unwrap(),
+            Response::Record { value, .. } if value == b"cushion"
+        ));
+    }
+
+    #[test]
+    fn mmap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let options = || {
+            Builder::new()
+                .memtable_size(64)
+                .read_mode(ReadMode::Mmap)
+                .build()
+                .unwrap()
+        };
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for i in 0..32 {
+                storage
+                    .set(format!("key{:02}", i).as_bytes(), b"value")
+                    .unwrap();
+            }
+            storage.compact().unwrap();
+            storage.del(b"key07").unwrap();
+        }
+        let storage = Storage::new(dir, options()).unwrap();
+        assert!(storage.levels.iter().flatten().all(|s| s.is_mapped()));
+        assert!(matches!(
+            storage.get(b"key31").unwrap(),
+            Response::Record { value, .. } if value == b"value"
+        ));
+        assert!(storage.get(b"key07").is_err());
+        assert_eq!(storage.range(b"key", b"kez").count(), 31);
+    }
+
+    #[test]
+    fn compression() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let options = |compression| {
+            Builder::new()
+                .memtable_size(64)
+                .compression(compression)
+                .build()
+                .unwrap()
+        };
+        // the segments of either codec are read whatever the option
+        for (i, compression) in [Compression::None, Compression::Lz4, Compression::None]
+            .into_iter()
+            .enumerate()
+        {
+            let mut storage = Storage::new(dir, options(compression)).unwrap();
+            for j in 0..16 {
+                storage
+                    .set(format!("key{}{:02}", i, j).as_bytes(), b"value")
+                    .unwrap();
+            }
+            storage.wait_flush().unwrap();
+            assert_eq!(storage.range(b"key", b"kez").count(), 16 * (i + 1));
+        }
+        let mut storage = Storage::new(dir, options(Compression::Lz4)).unwrap();
+        storage.compact().unwrap();
+        assert_eq!(storage.range(b"key", b"kez").count(), 48);
+    }
+
+    #[test]
+    fn range() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut storage = Storage::new(dir, options()).unwrap();
+        for i in 0..10 {
+            storage
+                .set(format!("key{}", i).as_bytes(), b"old value")
+                .unwrap();
+        }
+        storage.flush().unwrap();
+        storage.set(b"key3", b"new value").unwrap();
+        storage.del(b"key5").unwrap();
+        let pairs = storage
+            .range(b"key2", b"key7")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"key2".to_vec(), b"old value".to_vec()),
+                (b"key3".to_vec(), b"new value".to_vec()),
+                (b"key4".to_vec(), b"old value".to_vec()),
+                (b"key6".to_vec(), b"old value".to_vec()),
+            ]
+        );
+        assert_eq!(storage.range(b"key7", b"key2").count(), 0);
+        assert!(matches!(
+            storage.scan(b"key", b"kez", Some(3), None).unwrap(),
+            Response::Records { records } if records.len() == 3
+        ));
+    }
+
+    #[test]
+    fn snapshot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut storage = Storage::new(dir, options()).unwrap();
+        storage.set(b"needle", b"hay").unwrap();
+        for i in 0..8 {
+            storage
+                .set(format!("key{}", i).as_bytes(), b"old value")
+                .unwrap();
+        }
+        let snapshot = storage.snapshot();
+        storage.set(b"needle", b"straw").unwrap();
+        storage.del(b"key0").unwrap();
+        // enough flushes for a compaction, which waits for the snapshot
+        for i in 0..64 {
+            storage
+                .set(format!("key{}", i).as_bytes(), b"new value")
+                .unwrap();
+        }
+        storage.wait_flush().unwrap();
+        storage.sweep().unwrap();
+        assert!(storage.levels[0].len() >= storage.options.compaction_trigger);
+        assert!(storage.compact().is_err());
+        assert_eq!(snapshot.seq, 9);
+        assert!(matches!(
+            storage.get_at(b"needle", &snapshot).unwrap(),
+            Response::Record { value, .. } if value == b"hay"
+        ));
+        assert!(matches!(
+            storage.get(b"needle").unwrap(),
+            Response::Record { value, .. } if value == b"straw"
+        ));
+        let pairs = storage
+            .range_at(b"key", b"kez", &snapshot)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 8);
+        assert!(pairs.iter().all(|(_, value)| value == b"old value"));
+        drop(snapshot);
+        storage.compact().unwrap();
+        assert_eq!(storage.range(b"key", b"kez").count(), 64);
+        // the numbering goes on after a restart
+        storage.set(b"pin", b"cushion").unwrap();
+        let seq = storage.seq;
+        drop(storage);
+        let storage = Storage::new(dir, options()).unwrap();
+        assert_eq!(storage.seq, seq);
+        assert_eq!(storage.snapshot().seq, seq);
+    }
+
+    #[test]
+    fn restore_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        // no compaction, the keys are spread over many segments
+        let options = || {
+            Builder::new()
+                .memtable_size(64)
+                .compaction_trigger(64)
+                .build()
+                .unwrap()
+        };
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for i in 0..32 {
+                storage
+                    .set(format!("key{}", i).as_bytes(), b"old value")
+                    .unwrap();
+            }
+            storage.set(b"key1", b"new value").unwrap();
+            for i in 32..64 {
+                storage
+                    .set(format!("key{}", i).as_bytes(), b"old value")
+                    .unwrap();
+            }
+        }
+        assert!(
+            FileSystem::scan_data_files(tmp.path(), "data")
+                .unwrap()
+                .len()
+                > 1
+        );
+        let storage = Storage::new(dir, options()).unwrap();
+        for i in 0..64 {
+            let expected: &[u8] = if i == 1 { b"new value" } else { b"old value" };
+            assert!(matches!(
+                storage.get(format!("key{}", i).as_bytes()).unwrap(),
+                Response::Record { value, .. } if value == expected
+            ));
+        }
+    }
+
+    #[test]
+    fn manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for i in 0..64 {
+                storage
+                    .set(format!("key{:02}", i).as_bytes(), b"value")
+                    .unwrap();
+            }
+        }
+        // a stray file is left alone, a segment the manifest doesn't know is garbage
+        std::fs::write(tmp.path().join("data.bak"), b"backup").unwrap();
+        std::fs::write(tmp.path().join("data.0.999"), b"torn").unwrap();
+        {
+            let storage = Storage::new(dir, options()).unwrap();
+            assert_eq!(storage.range(b"key", b"kez").count(), 64);
+            assert!(tmp.path().join("data.bak").exists());
+            assert!(!tmp.path().join("data.0.999").exists());
+        }
+        // a directory from before the manifest is scanned once
+        std::fs::remove_file(tmp.path().join("MANIFEST")).unwrap();
+        let mut storage = Storage::new(dir, options()).unwrap();
+        assert_eq!(storage.range(b"key", b"kez").count(), 64);
+        storage.compact().unwrap();
+        let live = storage.levels.iter().flatten().count();
+        drop(storage);
+        let storage = Storage::new(dir, options()).unwrap();
+        assert_eq!(storage.levels.iter().flatten().count(), live);
+        assert_eq!(
+            FileSystem::scan_data_files(tmp.path(), "data")
+                .unwrap()
+                .len(),
+            live
+        );
+        assert_eq!(storage.range(b"key", b"kez").count(), 64);
+    }
+
+    #[test]
+    fn temporaries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for i in 0..16 {
+                storage
+                    .set(format!("key{:02}", i).as_bytes(), b"value")
+                    .unwrap();
+            }
+        }
+        // what a crash in the middle of a flush and of a manifest rewrite leaves behind
+        std::fs::write(tmp.path().join("data.0.99.tmp"), b"half a segment").unwrap();
+        std::fs::write(tmp.path().join("MANIFEST.tmp"), b"half a manifest").unwrap();
+        let storage = Storage::new(dir, options()).unwrap();
+        assert_eq!(storage.range(b"key", b"kez").count(), 16);
+        assert!(std::fs::read_dir(tmp.path()).unwrap().all(|entry| entry
+            .unwrap()
+            .path()
+            .extension()
+            .unwrap_or_default()
+            != "tmp"));
+    }
+
+    #[test]
+    fn ttl() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mut storage = Storage::new(dir, options()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(b"gone", b"soon", Duration::ZERO);
+        for i in 0..16 {
+            let key = format!("key{:02}", i);
+            batch.put_with_ttl(key.as_bytes(), b"value", Duration::from_millis(200));
+        }
+        storage.write_batch(batch).unwrap();
+        assert!(storage.get(b"gone").is_err());
+        assert_eq!(storage.range(b"key", b"kez").count(), 16);
+        // the batch is flushed before the next write, the expiring keys go with it
+        storage.set(b"pin", b"cushion").unwrap();
+        storage.wait_flush().unwrap();
+        let expiring = |storage: &Storage| {
+            storage
+                .levels
+                .iter()
+                .flatten()
+                .filter(|segment| segment.footer.expires != 0)
+                .count()
+        };
+        assert_eq!(expiring(&storage), 1);
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(storage.range(b"key", b"kez").count(), 0);
+        assert!(storage.get(b"key00").is_err());
+
+        // an expired key of the memtable is swept into a tombstone
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(b"late", b"value", Duration::ZERO);
+        storage.write_batch(batch).unwrap();
+        storage.sweep().unwrap();
+        assert!(storage.memtable[b"late".as_slice()].tombstone);
+        assert_eq!(expiring(&storage), 0);
+        assert!(storage.get(b"pin").is_ok());
+        drop(storage);
+        let storage = Storage::new(dir, options()).unwrap();
+        assert!(storage.get(b"gone").is_err());
+        assert!(storage.get(b"late").is_err());
+        assert_eq!(storage.range(b"key", b"kez").count(), 0);
+    }
+
+    #[test]
+    fn truncate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(dir, options()).unwrap();
+            for i in 0..64 {
+                storage
+                    .set(format!("key{:02}", i).as_bytes(), b"value")
+                    .unwrap();
+            }
+            let snapshot = storage.snapshot();
+            storage.truncate().unwrap();
+            assert!(storage.get(b"key63").is_err());
+            assert_eq!(storage.range(b"key", b"kez").count(), 0);
+            // the snapshot reads the segments it holds on to
+            assert!(storage.get_at(b"key00", &snapshot).is_ok());
+            storage.set(b"needle", b"hay").unwrap();
+        }
+        // the old segments are gone, the log of the truncated writes with them
+        let mut storage = Storage::new(dir, options()).unwrap();
+        assert_eq!(
+            FileSystem::scan_data_files(tmp.path(), "data")
+                .unwrap()
+                .len(),
+            0
+        );
+        assert!(storage.get(b"key00").is_err());
+        assert!(storage.get(b"needle").is_ok());
+        storage.truncate().unwrap();
+        // the writes after a restart are numbered after the truncation, they are not taken for truncated ones
+        drop(storage);
+        let mut storage = Storage::new(dir, options()).unwrap();
+        storage.set(b"pin", b"cushion").unwrap();
+        drop(storage);
+        let storage = Storage::new(dir, options()).unwrap();
+        assert!(storage.get(b"needle").is_err());
+        assert!(storage.get(b"pin").is_ok());
+    }
+
+    #[test]
+    fn move_to() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (old, new) = (tmp.path().join("old"), tmp.path().join("new"));
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::create_dir_all(&new).unwrap();
+        std::fs::write(old.join("notes"), b"not ours").unwrap();
+        let mut storage = Storage::new(old.to_str().unwrap(), options()).unwrap();
+        for i in 0..64 {
+            storage
+                .set(format!("key{:02}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        // the target must be an empty directory
+        assert!(storage
+            .move_to(old.join("notes").to_str().unwrap())
+            .is_err());
+        assert!(storage.move_to(old.to_str().unwrap()).is_err());
+        {
+            let _snapshot = storage.snapshot();
+            assert!(storage.move_to(new.to_str().unwrap()).is_err());
+        }
+        storage.move_to(new.to_str().unwrap()).unwrap();
+        assert_eq!(storage.range(b"key", b"kez").count(), 64);
+        storage.set(b"needle", b"hay").unwrap();
+        let names = |dir: &Path| {
+            let mut names: Vec<_> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&old), vec!["notes"]);
+        assert!(names(&new).contains(&"MANIFEST".to_string()));
+        drop(storage);
+        let storage = Storage::new(new.to_str().unwrap(), options()).unwrap();
+        assert_eq!(storage.range(b"key", b"kez").count(), 64);
+        assert!(storage.get(b"needle").is_ok());
+    }
+
+    #[test]
+    fn attach_to() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (first, second) = (tmp.path().join("first"), tmp.path().join("second"));
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+        let mut storage = Storage::new(first.to_str().unwrap(), options()).unwrap();
+        storage.set(b"needle", b"hay").unwrap();
+        storage.attach_to(second.to_str().unwrap()).unwrap();
+        assert!(storage.get(b"needle").is_err());
+        storage.set(b"pin", b"cushion").unwrap();
+        assert!(storage
+            .attach_to(tmp.path().join("missing").to_str().unwrap())
+            .is_err());
+        assert!(storage.get(b"pin").is_ok());
+        // the memtable was flushed when the storage left, nothing waits in the log
+        storage.attach_to(first.to_str().unwrap()).unwrap();
+        assert!(storage.get(b"needle").is_ok());
+        assert!(storage.get(b"pin").is_err());
+        assert_eq!(
+            FileSystem::scan_data_files(&second, "data").unwrap().len(),
+            1
+        );
+        // a directory the storage can't be restored from is left for the one it came from
+        let mut manifest = std::fs::read(second.join("MANIFEST")).unwrap();
+        manifest[10] ^= 1;
+        std::fs::write(second.join("MANIFEST"), manifest).unwrap();
+        assert!(storage.attach_to(second.to_str().unwrap()).is_err());
+        assert!(storage.get(b"needle").is_ok());
+        storage.set(b"thread", b"spool").unwrap();
+        drop(storage);
+        let storage = Storage::new(first.to_str().unwrap(), options()).unwrap();
+        assert!(storage.get(b"thread").is_ok());
+    }
 }
@@ -1,3 +1,5 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 #[allow(dead_code)]
 pub(crate) fn eq_u8(lhs: &[u8], rhs: &[u8]) -> bool {
     if lhs.len() != rhs.len() {
@@ -10,3 +12,10 @@ pub(crate) fn eq_u8(lhs: &[u8], rhs: &[u8]) -> bool {
     }
     true
 }
+
+/// the current time in milliseconds since the unix epoch, which is what expiries are measured in
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -1,3 +1,5 @@
+use crate::Error;
+use dpdb_core::{Builder, Options};
 use once_cell::sync::OnceCell;
 
 pub static CF: OnceCell<Config> = OnceCell::new();
@@ -5,9 +7,39 @@ pub static CF: OnceCell<Config> = OnceCell::new();
 #[derive(Clone, Debug)]
 pub struct Config {
     pub path: String,
+    pub options: Options,
+    // nothing is written to `path`, see `--in-memory`
+    pub in_memory: bool,
 }
 
-pub fn init(matches: &clap::ArgMatches) {
-    let path = matches.value_of("path").unwrap().to_owned();
-    let _ = CF.set(Config { path });
+pub fn init(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let path = matches.value_of("path").unwrap_or_default().to_owned();
+    let in_memory = matches.is_present("in-memory");
+    // the flags left out keep their defaults
+    let mut builder = Builder::new();
+    if let Some(bytes) = matches.value_of("memtable-size") {
+        builder = builder.memtable_size(bytes.parse().map_err(|_| Error::Config)?);
+    }
+    if let Some(file) = matches.value_of("file") {
+        builder = builder.file(file);
+    }
+    if let Some(sync) = matches.value_of("sync") {
+        builder = builder.sync(sync.parse()?);
+    }
+    if let Some(mode) = matches.value_of("read-mode") {
+        builder = builder.read_mode(mode.parse()?);
+    }
+    if let Some(compression) = matches.value_of("compression") {
+        builder = builder.compression(compression.parse()?);
+    }
+    if let Some(rate) = matches.value_of("bloom-false-positive-rate") {
+        builder = builder.bloom_false_positive_rate(rate.parse().map_err(|_| Error::Config)?);
+    }
+    let options = builder.build()?;
+    let _ = CF.set(Config {
+        path,
+        options,
+        in_memory,
+    });
+    Ok(())
 }
@@ -18,13 +18,65 @@ fn path_valid(v: &str) -> Result<(), Error> {
 pub fn init() {
     let setup = Command::new("Dpdb says hello");
     let setup = setup.subcommand(
-        Command::new("start").arg(
-            Arg::new("path")
-                .index(1)
-                .required(true)
-                .validator(path_valid)
-                .help("Database path used for storing data"),
-        ),
+        Command::new("start")
+            .arg(
+                Arg::new("path")
+                    .index(1)
+                    .required_unless_present("in-memory")
+                    .validator(path_valid)
+                    .help("Database path used for storing data"),
+            )
+            .arg(
+                Arg::new("memtable-size")
+                    .long("memtable-size")
+                    .takes_value(true)
+                    .env("DPDB_MEMTABLE_SIZE")
+                    .help("Bytes the memtable holds before it is flushed into a segment"),
+            )
+            .arg(
+                Arg::new("file")
+                    .long("file")
+                    .takes_value(true)
+                    .env("DPDB_FILE")
+                    .help("Base name of the segment files, e.g. data for data.0, data.1, ..."),
+            )
+            .arg(
+                Arg::new("sync")
+                    .long("sync")
+                    .takes_value(true)
+                    .possible_values(["always", "never"])
+                    .env("DPDB_SYNC")
+                    .help("Whether every write is synced to the disk before it is acknowledged"),
+            )
+            .arg(
+                Arg::new("read-mode")
+                    .long("read-mode")
+                    .takes_value(true)
+                    .possible_values(["buffered", "mmap"])
+                    .env("DPDB_READ_MODE")
+                    .help("Whether segments are read with seek and read, or mapped into memory"),
+            )
+            .arg(
+                Arg::new("compression")
+                    .long("compression")
+                    .takes_value(true)
+                    .possible_values(["none", "lz4"])
+                    .env("DPDB_COMPRESSION")
+                    .help("How the blocks of new segment files are compressed"),
+            )
+            .arg(
+                Arg::new("bloom-false-positive-rate")
+                    .long("bloom-false-positive-rate")
+                    .takes_value(true)
+                    .env("DPDB_BLOOM_FALSE_POSITIVE_RATE")
+                    .help("Share of the lookups of missing keys a segment's Bloom filter lets through, e.g. 0.01"),
+            )
+            .arg(
+                Arg::new("in-memory")
+                    .long("in-memory")
+                    .env("DPDB_IN_MEMORY")
+                    .help("Keep the data in memory only, it is gone once the server stops"),
+            ),
     );
     #[cfg(feature = "repl")]
     let setup = setup.subcommand(Command::new("connect"));
@@ -1,14 +1,19 @@
-use log::info;
+use log::{info, warn};
 
 use super::config;
 use crate::db;
 use crate::net::receiver::Receiver;
 use crate::Error;
+use dpdb_core::Session;
 use std::net::SocketAddr;
+use std::time::Duration;
+
+// how often the expired keys are reclaimed
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 pub async fn init(matches: &clap::ArgMatches) -> Result<(), Error> {
-    config::init(matches);
+    config::init(matches)?;
     db::init().await?;
     start("127.0.0.1:5860").await?;
     Ok(())
@@ -18,12 +23,26 @@ async fn start(addr: &str) -> Result<(), Error> {
     let addr = addr.to_string().parse::<SocketAddr>()?;
     let receiver = Receiver::new(addr).await?;
     let db = db::DB.get().unwrap();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            // the compaction of the sweep is blocking I/O, it runs off the workers of the runtime
+            match tokio::task::spawn_blocking(move || db.blocking_lock().sweep()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("sweep failed: {}", e),
+                Err(e) => warn!("sweep failed: {}", e),
+            }
+        }
+    });
     loop {
         let mut rpcend = receiver.new_conn().await?;
         tokio::spawn(async move {
+            // the snapshot of the client is released when it disconnects
+            let mut session = Session::default();
             while let Ok(Some(line)) = rpcend.receive().await {
                 info!("sql: {}", &line);
-                let response = db.lock().await.execute(&line);
+                let response = db.lock().await.execute_in(&mut session, &line);
                 let response = response.serialize().unwrap_or_else(|_| "".to_string());
                 // what else do you want in a loop?
                 let _ = rpcend.send(response.as_str()).await;
@@ -58,6 +77,8 @@ mod test {
     async fn test() -> Result<(), Error> {
         let _ = CF.set(Config {
             path: String::from("/media/root_/SLC16/test"),
+            options: Default::default(),
+            in_memory: false,
         });
         db::init().await?;
         tokio::spawn(async move {
@@ -8,7 +8,10 @@ pub static DB: OnceCell<Mutex<Executor>> = OnceCell::new();
 
 pub async fn init() -> Result<(), Error> {
     let opt = CF.get().unwrap();
-    let executor = Executor::new(&opt.path).await?;
+    let executor = match opt.in_memory {
+        true => Executor::in_memory(opt.options.clone()).await?,
+        false => Executor::with_options(&opt.path, opt.options.clone()).await?,
+    };
     let _ = DB.set(Mutex::new(executor));
     Ok(())
 }
@@ -16,6 +16,8 @@ pub enum Error {
     // todo: should move this to dpdb_core
     #[error("File system error")]
     Fs,
+    #[error("Invalid configuration")]
+    Config,
 }
 
 impl From<ReadlineError> for Error {